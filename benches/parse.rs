@@ -0,0 +1,109 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use simple_ast::markdown_rules::*;
+use simple_ast::regex::{Captures, LimitExceeded};
+use simple_ast::{MarkdownNode, MatchContext, ParseSpec, Parser, Rule, RuleError};
+
+const CHAT: &[&str] = &[
+    "hey, did you see the new build?",
+    "**yes** it's _way_ faster now <@123456789>",
+    "> that's what she said\nlol",
+    "try `cargo bench` and check ||the results|| :)",
+    "<:pog:123456789012345678> ~~broken~~ fixed in <#987654321>",
+];
+
+// Hides a rule's first bytes, so the parser has to try it everywhere
+struct Unfiltered(Box<dyn Rule<MarkdownNode>>);
+
+impl Rule<MarkdownNode> for Unfiltered {
+    fn accept_match(&self, context: &MatchContext<MarkdownNode>, state: &()) -> bool {
+        self.0.accept_match(context, state)
+    }
+
+    fn allows_nesting(&self) -> bool {
+        self.0.allows_nesting()
+    }
+
+    fn parse(
+        &self,
+        captures: &Captures,
+        state: &mut (),
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        self.0.parse(captures, state)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        self.0.captures(src)
+    }
+
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        self.0.captures_at(src, start, end)
+    }
+}
+
+fn rules() -> Vec<Box<dyn Rule<MarkdownNode>>> {
+    vec![
+        Box::new(Escape),
+        Box::new(Newline),
+        Box::new(Emoji),
+        Box::new(Timestamp),
+        Box::new(ChannelMention),
+        Box::new(UserMention),
+        Box::new(RoleMention),
+        Box::new(Bold),
+        Box::new(Underline),
+        Box::new(Italic),
+        Box::new(Strikethrough),
+        Box::new(Spoiler),
+        Box::new(BlockQuote),
+        Box::new(Code),
+        Box::new(InlineCode),
+        Box::new(Text),
+    ]
+}
+
+fn parsers() -> Vec<(&'static str, Parser<MarkdownNode>)> {
+    let unfiltered = rules()
+        .into_iter()
+        .map(|rule| Box::new(Unfiltered(rule)) as Box<dyn Rule<MarkdownNode>>)
+        .collect();
+    vec![
+        ("dispatch", Parser::with_rules(rules())),
+        ("no dispatch", Parser::with_rules(unfiltered)),
+    ]
+}
+
+fn long_message() -> String {
+    let mut message = String::new();
+    while message.len() < 4000 {
+        message.push_str(CHAT[message.len() % CHAT.len()]);
+        message.push('\n');
+    }
+    message.truncate(4000);
+    message
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let long = long_message();
+    let mut group = c.benchmark_group("parse");
+    for (name, parser) in parsers() {
+        group.bench_with_input(BenchmarkId::new("chat", name), CHAT, |b, messages| {
+            b.iter(|| {
+                for message in messages {
+                    parser.parse(message);
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("4000 chars", name), &long, |b, message| {
+            b.iter(|| parser.parse(message))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
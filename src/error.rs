@@ -0,0 +1,74 @@
+use std::fmt;
+
+/// Why a rule couldn't turn its match into a `ParseSpec`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleError {
+    /// The rule doesn't want this match, the next rule is tried instead
+    Declined,
+    /// A capture group the rule relies on didn't take part in the match
+    MissingGroup(usize),
+    /// Like `MissingGroup`, for a named group
+    MissingNamedGroup(String),
+    /// The returned range doesn't lie within the match
+    InvalidRange(usize, usize),
+    /// The matched text couldn't be converted, e.g. an id too large for a u64
+    Invalid(String),
+    /// The returned spec names a nested rule set the parser doesn't have
+    UnknownRuleSet(String),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleError::Declined => write!(f, "match declined"),
+            RuleError::MissingGroup(group) => write!(f, "capture group {} did not match", group),
+            RuleError::MissingNamedGroup(name) => {
+                write!(f, "capture group {:?} did not match", name)
+            }
+            RuleError::InvalidRange(start, end) => write!(f, "invalid range {}..{}", start, end),
+            RuleError::Invalid(reason) => write!(f, "{}", reason),
+            RuleError::UnknownRuleSet(name) => write!(f, "no rule set named {:?}", name),
+        }
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+/// A rule failure, with the byte offset of the match that caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub error: RuleError,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.error, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A `RuleSet` operation that named a rule it couldn't use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleSetError {
+    /// There is no rule with this name
+    Unknown(String),
+    /// There already is a rule with this name
+    Duplicate(String),
+}
+
+impl fmt::Display for RuleSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleSetError::Unknown(name) => write!(f, "no rule named {:?}", name),
+            RuleSetError::Duplicate(name) => write!(f, "a rule named {:?} already exists", name),
+        }
+    }
+}
+
+impl std::error::Error for RuleSetError {}
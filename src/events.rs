@@ -0,0 +1,668 @@
+use crate::regex::LimitExceeded;
+use crate::rule_set::RuleId;
+use crate::Edit;
+use crate::{
+    Limit, MatchContext, Node, Outcome, ParseError, ParseSpec, Parser, RuleAttempt, RuleError,
+    Span, Spanned, Trace, TraceStep,
+};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// A step of a parse, in document order
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event<'s, T> {
+    /// A nonterminal node, without children yet. Every event up to the
+    /// matching `End` belongs to it.
+    Start(Spanned<T>),
+    /// Source text that no rule matched
+    Text(Spanned<&'s str>),
+    /// A terminal node
+    Leaf(Spanned<T>),
+    /// The last started nonterminal is finished, carries its span
+    End(Span),
+}
+
+// An event along with the rule that caused it, if any
+pub(crate) type RuledEvent<'s, T> = (Event<'s, T>, Option<RuleId>);
+
+// Where a parse is between two steps, everything needed to pick it back up
+// there
+#[derive(Clone)]
+pub(crate) struct Checkpoint<T, S> {
+    pub(crate) index: usize,
+    pub(crate) fallback_start: Option<usize>,
+    state: S,
+    pub(crate) last_capture: Option<Span>,
+    previous: Option<Spanned<T>>,
+    stack: Arc<Stack<T, S>>,
+}
+
+// The open frames and nodes. They only change when a frame is opened or
+// closed, so the checkpoints in between share them.
+pub(crate) struct Stack<T, S> {
+    // The innermost frame is kept as it was opened, its position is in the
+    // checkpoint
+    frames: Vec<Frame<S>>,
+    ancestors: Vec<Spanned<T>>,
+    // The last node at each depth but the innermost
+    siblings: Vec<Option<Spanned<T>>>,
+}
+
+impl<T: Node<T> + Clone + PartialEq, S: Clone + PartialEq> Checkpoint<T, S> {
+    // How many nodes are open
+    pub(crate) fn depth(&self) -> usize {
+        self.stack.ancestors.len()
+    }
+
+    // The same point in the source after `edit`, `None` if it depends on text
+    // the edit replaced
+    pub(crate) fn moved(
+        &self,
+        edit: &Edit,
+        stacks: &mut MovedStacks<T, S>,
+    ) -> Option<Checkpoint<T, S>> {
+        let stack = match &stacks.last {
+            Some((old, new)) if Arc::ptr_eq(old, &self.stack) => new.clone(),
+            _ => {
+                let new = self.stack.moved(edit).map(Arc::new);
+                stacks.last = Some((self.stack.clone(), new.clone()));
+                new
+            }
+        }?;
+        Some(Checkpoint {
+            index: edit.moved(self.index)?,
+            fallback_start: match self.fallback_start {
+                Some(start) => Some(edit.moved(start)?),
+                None => None,
+            },
+            state: self.state.clone(),
+            last_capture: match self.last_capture {
+                Some(span) => Some(edit.moved_span(span)?),
+                None => None,
+            },
+            previous: match &self.previous {
+                Some(node) => Some(edit.moved_node(node)?),
+                None => None,
+            },
+            stack,
+        })
+    }
+
+    // Whether `new` is this checkpoint moved by `edit`
+    pub(crate) fn moved_to(&self, new: &Checkpoint<T, S>, edit: &Edit) -> bool {
+        edit.moved(self.index) == Some(new.index)
+            && self.fallback_start.map(|start| edit.moved(start)) == new.fallback_start.map(Some)
+            && self.last_capture.map(|span| edit.moved_span(span)) == new.last_capture.map(Some)
+            && nodes_moved_to(self.previous.as_ref(), new.previous.as_ref(), edit)
+            && self.state == new.state
+            && self.stack.moved_to(&new.stack, edit)
+    }
+}
+
+impl<T: Node<T> + Clone + PartialEq, S: Clone + PartialEq> Stack<T, S> {
+    fn moved(&self, edit: &Edit) -> Option<Stack<T, S>> {
+        Some(Stack {
+            frames: (self.frames.iter().enumerate())
+                .map(|(depth, frame)| frame.moved(edit, depth + 1 == self.frames.len()))
+                .collect::<Option<_>>()?,
+            ancestors: self
+                .ancestors
+                .iter()
+                .map(|node| edit.moved_node(node))
+                .collect::<Option<_>>()?,
+            siblings: self
+                .siblings
+                .iter()
+                .map(|node| match node {
+                    Some(node) => edit.moved_node(node).map(Some),
+                    None => Some(None),
+                })
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    fn moved_to(&self, new: &Stack<T, S>, edit: &Edit) -> bool {
+        self.frames.len() == new.frames.len()
+            && self.ancestors.len() == new.ancestors.len()
+            && self.siblings.len() == new.siblings.len()
+            && (self.frames.iter().zip(&new.frames).enumerate())
+                .all(|(depth, (old, new))| old.moved_to(new, edit, depth + 1 == self.frames.len()))
+            && (self.ancestors.iter().zip(&new.ancestors))
+                .all(|(old, new)| edit.node_moved_to(old, new))
+            && (self.siblings.iter().zip(&new.siblings))
+                .all(|(old, new)| nodes_moved_to(old.as_ref(), new.as_ref(), edit))
+    }
+}
+
+fn nodes_moved_to<T: Node<T> + PartialEq>(
+    old: Option<&Spanned<T>>,
+    new: Option<&Spanned<T>>,
+    edit: &Edit,
+) -> bool {
+    match (old, new) {
+        (Some(old), Some(new)) => edit.node_moved_to(old, new),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// Moves checkpoints by one edit, moving each stack once however many
+// checkpoints in a row share it
+pub(crate) struct MovedStacks<T, S> {
+    last: Option<MovedStack<T, S>>,
+}
+
+// A stack and where the edit moved it, if anywhere
+type MovedStack<T, S> = (Arc<Stack<T, S>>, Option<Arc<Stack<T, S>>>);
+
+impl<T, S> Default for MovedStacks<T, S> {
+    fn default() -> Self {
+        MovedStacks { last: None }
+    }
+}
+
+// A nonterminal whose content is still being parsed
+#[derive(Clone, PartialEq)]
+struct Frame<S> {
+    has_node: bool,
+    rule: Option<RuleId>,
+    // The rule set the content is parsed with
+    set: usize,
+    span: Span,
+    content: Span,
+    // The state from before this node was opened, restored once it is closed
+    saved_state: Option<S>,
+    // Where the run of text that no rule matched started, if there is one
+    fallback_start: Option<usize>,
+    start_index: usize,
+    end_index: usize,
+}
+
+impl<S: Clone> Frame<S> {
+    // Unless the frame is the innermost one, `start_index` is where the frame
+    // inside it ends
+    fn moved(&self, edit: &Edit, innermost: bool) -> Option<Frame<S>> {
+        let start_index = if innermost {
+            edit.moved(self.start_index)?
+        } else {
+            edit.moved_end(self.start_index)?
+        };
+        Some(Frame {
+            span: edit.moved_span(self.span)?,
+            content: edit.moved_span(self.content)?,
+            saved_state: self.saved_state.clone(),
+            fallback_start: match self.fallback_start {
+                Some(start) => Some(edit.moved(start)?),
+                None => None,
+            },
+            start_index,
+            end_index: edit.moved_end(self.end_index)?,
+            ..*self
+        })
+    }
+
+    fn moved_to(&self, new: &Frame<S>, edit: &Edit, innermost: bool) -> bool
+    where
+        S: PartialEq,
+    {
+        self.moved(edit, innermost).as_ref() == Some(new)
+    }
+}
+
+/// An iterator over the `Event`s of a parse, see `Parser::events`
+pub struct Events<'p, 's, T: Node<T>, S> {
+    parser: &'p Parser<T, S>,
+    src: &'s str,
+    state: S,
+    strict: bool,
+    open: Vec<Frame<S>>,
+    queue: VecDeque<RuledEvent<'s, T>>,
+    last_capture: Option<Span>,
+    // The nodes currently open, without their children
+    ancestors: Vec<Spanned<T>>,
+    // The last node added at each depth, the top level first
+    siblings: Vec<Option<Spanned<T>>>,
+    limit_exceeded: Option<Limit>,
+    node_count: usize,
+    rule_attempts: usize,
+    trace: Option<Trace>,
+    // The open frames and nodes as of the last checkpoint, unless they changed
+    stack: Option<Arc<Stack<T, S>>>,
+    // The end of the text the last step depended on
+    reach: usize,
+}
+
+impl<'p, 's, T: Node<T> + Clone, S: Clone> Events<'p, 's, T, S> {
+    pub(crate) fn new(parser: &'p Parser<T, S>, src: &'s str, state: S, strict: bool) -> Self {
+        Events::within(parser, src, Span::new(0, src.len()), state, strict)
+    }
+
+    // Parses only `src[span]`, rules still see the text around it
+    pub(crate) fn within(
+        parser: &'p Parser<T, S>,
+        src: &'s str,
+        span: Span,
+        state: S,
+        strict: bool,
+    ) -> Self {
+        Events {
+            parser,
+            src,
+            state,
+            strict,
+            open: vec![Frame {
+                has_node: false,
+                rule: None,
+                set: 0,
+                span,
+                content: span,
+                saved_state: None,
+                fallback_start: None,
+                start_index: span.start,
+                end_index: span.end,
+            }],
+            queue: VecDeque::new(),
+            last_capture: None,
+            ancestors: Vec::new(),
+            siblings: vec![None],
+            limit_exceeded: None,
+            node_count: 0,
+            rule_attempts: 0,
+            trace: None,
+            stack: None,
+            reach: 0,
+        }
+    }
+
+    // Starts recording what every step does
+    pub(crate) fn record_trace(&mut self) {
+        self.trace = Some(Trace {
+            source: self.src.to_owned(),
+            steps: Vec::new(),
+        });
+    }
+
+    pub(crate) fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    /// The limit that cut the parse short, if any, so far
+    pub fn limit_exceeded(&self) -> Option<Limit> {
+        self.limit_exceeded
+    }
+
+    // Where the parse is, `None` once it's done. Only valid while no events
+    // are queued.
+    pub(crate) fn checkpoint(&mut self) -> Option<Checkpoint<T, S>> {
+        let frame = self.open.last()?;
+        let (index, fallback_start) = (frame.start_index, frame.fallback_start);
+        let (open, ancestors, siblings) = (&self.open, &self.ancestors, &self.siblings);
+        let stack = self.stack.get_or_insert_with(|| {
+            let mut frames = open.clone();
+            let innermost = frames.last_mut().expect("open frames must not be empty");
+            innermost.start_index = innermost.content.start;
+            innermost.fallback_start = None;
+            Arc::new(Stack {
+                frames,
+                ancestors: ancestors.clone(),
+                siblings: siblings[..siblings.len() - 1].to_vec(),
+            })
+        });
+        Some(Checkpoint {
+            index,
+            fallback_start,
+            state: self.state.clone(),
+            last_capture: self.last_capture,
+            previous: self.siblings.last().cloned().flatten(),
+            stack: stack.clone(),
+        })
+    }
+
+    // Picks the parse up at a checkpoint, of this parse or of an earlier one
+    // of the same text
+    pub(crate) fn restore(&mut self, checkpoint: &Checkpoint<T, S>) {
+        let stack = &checkpoint.stack;
+        self.open = stack.frames.clone();
+        let frame = self.open.last_mut().expect("open frames must not be empty");
+        frame.start_index = checkpoint.index;
+        frame.fallback_start = checkpoint.fallback_start;
+        self.ancestors = stack.ancestors.clone();
+        self.siblings = stack.siblings.clone();
+        self.siblings.push(checkpoint.previous.clone());
+        self.state = checkpoint.state.clone();
+        self.last_capture = checkpoint.last_capture;
+        self.queue.clear();
+        self.limit_exceeded = None;
+        self.stack = Some(stack.clone());
+    }
+
+    // How far into the source the last step looked, see `Rule::reach`
+    pub(crate) fn reach(&self) -> usize {
+        self.reach
+    }
+
+    pub(crate) fn pop_queued(&mut self) -> Option<RuledEvent<'s, T>> {
+        self.queue.pop_front()
+    }
+
+    pub(crate) fn next_event(&mut self) -> Result<Option<Event<'s, T>>, ParseError> {
+        Ok(self.next_with_rule()?.map(|(event, _)| event))
+    }
+
+    pub(crate) fn next_with_rule(&mut self) -> Result<Option<RuledEvent<'s, T>>, ParseError> {
+        while self.queue.is_empty() {
+            if !self.step()? {
+                return Ok(None);
+            }
+        }
+        Ok(self.queue.pop_front())
+    }
+
+    // Advances the parse by one rule match (or one character of fallback
+    // text, or closing one nonterminal), returns false once it's done
+    pub(crate) fn step(&mut self) -> Result<bool, ParseError> {
+        let frame = match self.open.last() {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+
+        if frame.start_index >= frame.end_index {
+            let end_index = frame.end_index;
+            self.reach = end_index;
+            self.flush_fallback(end_index);
+            let frame = self.open.pop().expect("open frames must not be empty");
+            self.stack = None;
+            if let Some(saved_state) = frame.saved_state {
+                self.state = saved_state;
+            }
+            if frame.has_node {
+                self.queue.push_back((Event::End(frame.span), frame.rule));
+                self.close_node();
+            }
+            return Ok(true);
+        }
+
+        let src = self.src;
+        let offset = frame.start_index;
+        let inspection_source = &src[frame.start_index..frame.end_index];
+        let next_char = inspection_source.chars().next().map_or(1, char::len_utf8);
+        self.reach = offset + next_char;
+
+        if self.limit_exceeded.is_none() && self.match_rules(offset, inspection_source)? {
+            return Ok(true);
+        }
+
+        // Nothing matched, so keep the next character as plain text, or the
+        // whole rest of the node once a limit has been hit
+        let frame = self.open.last_mut().expect("open frames must not be empty");
+        frame.fallback_start.get_or_insert(offset);
+        frame.start_index = if self.limit_exceeded.is_some() {
+            frame.end_index
+        } else {
+            offset + next_char
+        };
+        Ok(true)
+    }
+
+    // Tries each rule at `offset` and applies the first one that matches,
+    // returns whether there was one
+    fn match_rules(
+        &mut self,
+        offset: usize,
+        inspection_source: &'s str,
+    ) -> Result<bool, ParseError> {
+        let parser = self.parser;
+        let set = self.open.last().expect("open frames must not be empty").set;
+        if let Some(trace) = &mut self.trace {
+            trace.steps.push(TraceStep {
+                range: Span::new(offset, offset + inspection_source.len()),
+                attempts: Vec::new(),
+            });
+        }
+        let candidates = &parser.rules.set(set).dispatch[inspection_source.as_bytes()[0] as usize];
+        for &index in candidates {
+            let id = RuleId { set, index };
+            let rule = parser.rules.rule(id);
+            if !rule.allows_nesting() && self.open.iter().any(|frame| frame.rule == Some(id)) {
+                continue;
+            }
+            if let Some(max_rule_attempts) = parser.limits.max_rule_attempts {
+                if self.rule_attempts >= max_rule_attempts {
+                    self.record(id, Outcome::LimitExceeded(Limit::RuleAttempts));
+                    self.limit_exceeded = Some(Limit::RuleAttempts);
+                    return Ok(false);
+                }
+            }
+            self.rule_attempts += 1;
+
+            let end = offset + inspection_source.len();
+            let result = rule.captures_at(self.src, offset, end);
+            let reach = match &result {
+                Ok(captures) => {
+                    let match_end = captures.as_ref().and_then(|captures| captures.pos(0));
+                    let match_end = match_end.map(|(_, match_end)| match_end);
+                    (rule.reach(self.src, offset, match_end, end)).max(match_end.unwrap_or(offset))
+                }
+                Err(_) => end,
+            };
+            self.reach = self.reach.max(reach);
+            let matcher = match result {
+                Ok(Some(matcher)) => matcher,
+                Ok(None) => {
+                    self.record(id, Outcome::NoMatch);
+                    continue;
+                }
+                Err(LimitExceeded) => {
+                    self.record(id, Outcome::MatchLimitExceeded);
+                    continue;
+                }
+            };
+            let (matcher_start, matcher_end) = match matcher.pos(0) {
+                Some(pos) => pos,
+                None => {
+                    self.record(id, Outcome::NoMatch);
+                    continue;
+                }
+            };
+            let matched = Span::new(matcher_start, matcher_end);
+            // Text no rule matched becomes a node of its own before the match
+            let fallback;
+            let previous_sibling = match self.open.last().and_then(|frame| frame.fallback_start) {
+                Some(start) => {
+                    let span = Span::new(start, offset);
+                    fallback = Spanned::new(T::from_text(&self.src[span.range()]), span, span);
+                    Some(&fallback)
+                }
+                None => self.siblings.last().and_then(Option::as_ref),
+            };
+            let context = MatchContext {
+                last_capture: self.last_capture.map(|span| &self.src[span.range()]),
+                at_source_start: offset == 0,
+                at_line_start: offset == 0 || self.src[..offset].ends_with('\n'),
+                ancestors: &self.ancestors,
+                previous_sibling,
+            };
+            if !rule.accept_match(&context, &self.state) {
+                self.record(id, Outcome::Rejected(matched));
+                continue;
+            }
+
+            let saved_state = self.state.clone();
+            let parsed = rule.parse(&matcher, &mut self.state).and_then(|spec| {
+                check_range(&spec, self.src, matcher_start, matcher_end)?;
+                let child_set = match &spec.rule_set {
+                    Some(name) => parser
+                        .rules
+                        .nested_id(name)
+                        .ok_or_else(|| RuleError::UnknownRuleSet(name.clone()))?,
+                    None => set,
+                };
+                Ok((spec, child_set))
+            });
+            let (new_builder, child_set) = match parsed {
+                Ok(parsed) => parsed,
+                Err(error) => {
+                    self.state = saved_state;
+                    self.record(id, Outcome::Failed(matched, error.clone()));
+                    if self.strict && error != RuleError::Declined {
+                        return Err(ParseError {
+                            position: matcher_start,
+                            error,
+                        });
+                    }
+                    continue;
+                }
+            };
+
+            let span = matched;
+            let content = Span::new(new_builder.start_index, new_builder.end_index);
+
+            if let Some(limit) = self.check_limits(id, &new_builder, span, content) {
+                self.record(id, Outcome::LimitExceeded(limit));
+                self.state = saved_state;
+                self.limit_exceeded = Some(limit);
+                return Ok(false);
+            }
+
+            self.flush_fallback(offset);
+            self.last_capture = Some(span);
+            self.open
+                .last_mut()
+                .expect("open frames must not be empty")
+                .start_index = span.end;
+
+            let has_node = new_builder.root.is_some();
+            self.record(
+                id,
+                Outcome::Pushed {
+                    span,
+                    content,
+                    is_terminal: new_builder.is_terminal,
+                    has_node,
+                },
+            );
+            if let Some(root) = new_builder.root {
+                self.node_count += 1;
+                let node = Spanned::new(root, span, content);
+                if new_builder.is_terminal {
+                    self.set_previous(node.clone());
+                    self.queue.push_back((Event::Leaf(node), Some(id)));
+                } else {
+                    self.ancestors.push(node.clone());
+                    self.siblings.push(None);
+                    self.queue.push_back((Event::Start(node), Some(id)));
+                }
+            }
+            if !new_builder.is_terminal {
+                self.stack = None;
+                self.open.push(Frame {
+                    has_node,
+                    rule: Some(id),
+                    set: child_set,
+                    span,
+                    content,
+                    saved_state: Some(saved_state),
+                    fallback_start: None,
+                    start_index: content.start,
+                    end_index: content.end,
+                });
+            }
+
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn record(&mut self, rule: RuleId, outcome: Outcome) {
+        if let Some(step) = self.trace.as_mut().and_then(|trace| trace.steps.last_mut()) {
+            step.attempts.push(RuleAttempt {
+                rule: rule.index,
+                name: self.parser.rules.name(rule).to_owned(),
+                outcome,
+            });
+        }
+    }
+
+    fn check_limits(
+        &self,
+        id: RuleId,
+        spec: &ParseSpec<T>,
+        span: Span,
+        content: Span,
+    ) -> Option<Limit> {
+        let limits = &self.parser.limits;
+        // A rule opening the same content again inside itself, possibly with
+        // other rules doing the same in between, would nest forever
+        let repeated = self
+            .open
+            .iter()
+            .any(|frame| frame.rule == Some(id) && frame.content == content);
+        if span.is_empty() || repeated {
+            Some(Limit::NoProgress)
+        } else if !spec.is_terminal && limits.max_depth.is_some_and(|max| self.open.len() > max) {
+            Some(Limit::Depth)
+        } else if spec.root.is_some() && limits.max_nodes.is_some_and(|max| self.node_count >= max)
+        {
+            Some(Limit::Nodes)
+        } else {
+            None
+        }
+    }
+
+    // Emits the text no rule matched, up to `end_index`
+    fn flush_fallback(&mut self, end_index: usize) {
+        let frame = self.open.last_mut().expect("open frames must not be empty");
+        if let Some(start_index) = frame.fallback_start.take() {
+            let span = Span::new(start_index, end_index);
+            let text = &self.src[span.range()];
+            self.set_previous(Spanned::new(T::from_text(text), span, span));
+            self.queue
+                .push_back((Event::Text(Spanned::new(text, span, span)), None));
+        }
+    }
+
+    fn set_previous(&mut self, node: Spanned<T>) {
+        *self
+            .siblings
+            .last_mut()
+            .expect("siblings must not be empty") = Some(node);
+    }
+
+    // Leaves the innermost open node, which becomes the previous sibling of
+    // whatever comes after it
+    fn close_node(&mut self) {
+        let node = self.ancestors.pop().expect("a node must be open");
+        self.siblings.pop();
+        self.set_previous(node);
+    }
+}
+
+impl<'p, 's, T: Node<T> + Clone, S: Clone> Iterator for Events<'p, 's, T, S> {
+    type Item = Event<'s, T>;
+
+    fn next(&mut self) -> Option<Event<'s, T>> {
+        self.next_event()
+            .unwrap_or_else(|_| unreachable!("only strict parses fail"))
+    }
+}
+
+// The range a rule returns must lie within its own match, otherwise the
+// parser could slice outside the source or go backwards
+fn check_range<T: Node<T>>(
+    spec: &ParseSpec<T>,
+    src: &str,
+    matcher_start: usize,
+    matcher_end: usize,
+) -> Result<(), RuleError> {
+    let (start, end) = (spec.start_index, spec.end_index);
+    if matcher_start <= start
+        && start <= end
+        && end <= matcher_end
+        && src.is_char_boundary(start)
+        && src.is_char_boundary(end)
+    {
+        Ok(())
+    } else {
+        Err(RuleError::InvalidRange(start, end))
+    }
+}
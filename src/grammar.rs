@@ -0,0 +1,217 @@
+//! Rules loaded at runtime from a TOML or JSON description, e.g.
+//!
+//! ```toml
+//! [[rules]]
+//! name = "tag"
+//! pattern = '\{\{(\w+)\}\}'
+//! kind = "tag"
+//! content = 1
+//! terminal = true
+//! ```
+//!
+//! Patterns always match at the start of the remaining input, as if they
+//! began with `\G`. Lookbehind and `\b` see the input before it. The rules
+//! produce `GrammarNode`s.
+
+use crate::regex::{Captures, DefaultEngine, LimitExceeded, Regex, RegexEngine};
+use crate::{Node, ParseSpec, Rule, RuleError, RuleSet, RuleSetError, Spanned};
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Grammar {
+    pub rules: Vec<RuleDefinition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RuleDefinition {
+    pub name: String,
+    pub pattern: String,
+    /// The `kind` of the nodes the rule creates
+    pub kind: String,
+    /// The capture group holding the node's content, the whole match by
+    /// default. Nonterminals need a group, parsing their whole match again
+    /// would never get anywhere.
+    #[serde(default)]
+    pub content: usize,
+    /// Whether the content is kept as text instead of being parsed further
+    #[serde(default)]
+    pub terminal: bool,
+}
+
+/// A node of a loaded grammar
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrammarNode {
+    pub kind: String,
+    /// The content of terminal nodes
+    pub text: Option<String>,
+    pub children: Vec<Spanned<GrammarNode>>,
+}
+
+/// The kind of the nodes made for text no rule matched
+pub const TEXT_KIND: &str = "text";
+
+impl Node<GrammarNode> for GrammarNode {
+    fn get_children(&self) -> Option<&[Spanned<GrammarNode>]> {
+        match self.text {
+            Some(_) => None,
+            None => Some(&self.children),
+        }
+    }
+
+    fn get_children_mut(&mut self) -> Option<&mut Vec<Spanned<GrammarNode>>> {
+        match self.text {
+            Some(_) => None,
+            None => Some(&mut self.children),
+        }
+    }
+
+    fn add_child(&mut self, child: Spanned<GrammarNode>) {
+        self.children.push(child);
+    }
+
+    fn from_text(text: &str) -> GrammarNode {
+        GrammarNode {
+            kind: TEXT_KIND.to_owned(),
+            text: Some(text.to_owned()),
+            children: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GrammarError {
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    /// A rule's pattern didn't compile
+    Pattern {
+        rule: String,
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A nonterminal rule's content is its whole match
+    WholeMatchContent {
+        rule: String,
+    },
+    RuleSet(RuleSetError),
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrammarError::Toml(error) => write!(f, "invalid grammar: {}", error),
+            GrammarError::Json(error) => write!(f, "invalid grammar: {}", error),
+            GrammarError::Pattern { rule, error } => {
+                write!(f, "invalid pattern in rule {:?}: {}", rule, error)
+            }
+            GrammarError::WholeMatchContent { rule } => write!(
+                f,
+                "rule {:?} isn't terminal, so its content must be a capture group",
+                rule
+            ),
+            GrammarError::RuleSet(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+impl Grammar {
+    pub fn from_toml(src: &str) -> Result<Grammar, GrammarError> {
+        toml::from_str(src).map_err(GrammarError::Toml)
+    }
+
+    pub fn from_json(src: &str) -> Result<Grammar, GrammarError> {
+        serde_json::from_str(src).map_err(GrammarError::Json)
+    }
+
+    /// Compiles every rule, in the order they're listed
+    pub fn rule_set<S>(&self) -> Result<RuleSet<GrammarNode, S>, GrammarError> {
+        self.rule_set_with::<DefaultEngine, S>()
+    }
+
+    /// Like `rule_set`, compiling the patterns with the engine `E`
+    pub fn rule_set_with<E: RegexEngine, S>(
+        &self,
+    ) -> Result<RuleSet<GrammarNode, S>, GrammarError> {
+        let mut rules = RuleSet::new();
+        for definition in &self.rules {
+            let rule = GrammarRule::<E>::new(definition)?;
+            rules
+                .push(definition.name.clone(), rule)
+                .map_err(GrammarError::RuleSet)?;
+        }
+        Ok(rules)
+    }
+}
+
+/// A rule compiled from a `RuleDefinition`
+pub struct GrammarRule<E: RegexEngine = DefaultEngine> {
+    pattern: Regex<E>,
+    kind: String,
+    content: usize,
+    terminal: bool,
+}
+
+impl<E: RegexEngine> GrammarRule<E> {
+    pub fn new(definition: &RuleDefinition) -> Result<GrammarRule<E>, GrammarError> {
+        if !definition.terminal && definition.content == 0 {
+            return Err(GrammarError::WholeMatchContent {
+                rule: definition.name.clone(),
+            });
+        }
+        let pattern =
+            Regex::compile(&format!("\\G(?:{})", definition.pattern)).map_err(|error| {
+                GrammarError::Pattern {
+                    rule: definition.name.clone(),
+                    error: Box::new(error),
+                }
+            })?;
+        Ok(GrammarRule {
+            pattern,
+            kind: definition.kind.clone(),
+            content: definition.content,
+            terminal: definition.terminal,
+        })
+    }
+}
+
+impl<E: RegexEngine, S> Rule<GrammarNode, S> for GrammarRule<E> {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<GrammarNode>, RuleError> {
+        let (start, end) = captures.try_pos(self.content)?;
+        let node = GrammarNode {
+            kind: self.kind.clone(),
+            text: None,
+            children: Vec::new(),
+        };
+        Ok(if self.terminal {
+            let text = captures.try_at(self.content)?;
+            ParseSpec::create_terminal(
+                Some(GrammarNode {
+                    text: Some(text.to_owned()),
+                    ..node
+                }),
+                start,
+                end,
+            )
+        } else {
+            ParseSpec::create_nonterminal(Some(node), start, end)
+        })
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        self.pattern.captures(src)
+    }
+
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        self.pattern.captures_at(src, start, end)
+    }
+}
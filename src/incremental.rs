@@ -0,0 +1,489 @@
+use crate::events::{Checkpoint, MovedStacks};
+use crate::parser::TreeBuilder;
+use crate::rule_set::RuleId;
+use crate::{Event, Events, Node, Parser, Span, Spanned, Styled};
+use std::ops::Range;
+
+/// A change to the source: the bytes `start..old_end` were replaced by
+/// `start..new_end` of the new source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edit {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+impl Edit {
+    /// The edit that replaces `range` of the old source with `text`
+    pub fn replace(range: Range<usize>, text: &str) -> Edit {
+        Edit {
+            start: range.start,
+            old_end: range.end,
+            new_end: range.start + text.len(),
+        }
+    }
+
+    fn delta(&self) -> isize {
+        self.new_end as isize - self.old_end as isize
+    }
+
+    // Where `index` of the old source is in the new one, `None` if the edit
+    // replaced it. Text inserted at `index` goes after it, so a node whose
+    // content starts there still does.
+    pub(crate) fn moved(&self, index: usize) -> Option<usize> {
+        if index <= self.start {
+            Some(index)
+        } else {
+            self.moved_end(index)
+        }
+    }
+
+    // Like `moved`, but text inserted at `end` goes before it, so the source
+    // still ends at its end
+    pub(crate) fn moved_end(&self, end: usize) -> Option<usize> {
+        if end >= self.old_end {
+            Some(shift(end, self.delta()))
+        } else if end <= self.start {
+            Some(end)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn moved_span(&self, span: Span) -> Option<Span> {
+        Some(Span::new(
+            self.moved(span.start)?,
+            self.moved_end(span.end)?,
+        ))
+    }
+
+    pub(crate) fn moved_node<T: Node<T> + Clone>(&self, node: &Spanned<T>) -> Option<Spanned<T>> {
+        let mut node = node.clone();
+        self.move_node(&mut node)?;
+        Some(node)
+    }
+
+    fn move_node<T: Node<T>>(&self, node: &mut Spanned<T>) -> Option<()> {
+        node.span = self.moved_span(node.span)?;
+        node.content = self.moved_span(node.content)?;
+        if let Some(children) = node.get_children_mut() {
+            for child in children {
+                self.move_node(child)?;
+            }
+        }
+        Some(())
+    }
+
+    // Whether `new` is `old` moved by the edit, for nodes without children
+    pub(crate) fn node_moved_to<T: PartialEq>(&self, old: &Spanned<T>, new: &Spanned<T>) -> bool {
+        old.node == new.node
+            && self.moved_span(old.span) == Some(new.span)
+            && self.moved_span(old.content) == Some(new.content)
+    }
+}
+
+/// A parse that can be updated after an edit, see `Parser::reparse`
+pub struct IncrementalParse<T: Node<T> + std::fmt::Debug, S = ()> {
+    styled: Styled<T>,
+    // One for every step of the parse, in order
+    steps: Vec<Step<T, S>>,
+    // Every event of the parse, and where in them each top level node starts
+    events: Vec<Logged<T>>,
+    top_level: Vec<usize>,
+    len: usize,
+    rules_version: u64,
+}
+
+impl<T: Node<T> + std::fmt::Debug, S> IncrementalParse<T, S> {
+    pub fn styled(&self) -> &Styled<T> {
+        &self.styled
+    }
+
+    pub fn into_styled(self) -> Styled<T> {
+        self.styled
+    }
+}
+
+// Where the parse was before a step, and how far into the source the step
+// looked
+struct Step<T, S> {
+    checkpoint: Checkpoint<T, S>,
+    // How many events came before it
+    events: usize,
+    reach: usize,
+}
+
+// An event as it's kept for later reparses, without borrowing the source
+#[derive(Clone)]
+enum Logged<T> {
+    Start(Spanned<T>, Option<RuleId>),
+    Text(Span),
+    Leaf(Spanned<T>),
+    End(Span),
+}
+
+impl<T: Node<T> + Clone> Logged<T> {
+    fn new(event: &Event<T>, rule: Option<RuleId>) -> Logged<T> {
+        match event {
+            Event::Start(node) => Logged::Start(node.clone(), rule),
+            Event::Text(text) => Logged::Text(text.span),
+            Event::Leaf(node) => Logged::Leaf(node.clone()),
+            Event::End(span) => Logged::End(*span),
+        }
+    }
+
+    fn moved(&self, edit: &Edit) -> Option<Logged<T>> {
+        Some(match self {
+            Logged::Start(node, rule) => Logged::Start(edit.moved_node(node)?, *rule),
+            Logged::Text(span) => Logged::Text(edit.moved_span(*span)?),
+            Logged::Leaf(node) => Logged::Leaf(edit.moved_node(node)?),
+            Logged::End(span) => Logged::End(edit.moved_span(*span)?),
+        })
+    }
+
+    fn event<'s>(&self, src: &'s str) -> (Event<'s, T>, Option<RuleId>) {
+        match self {
+            Logged::Start(node, rule) => (Event::Start(node.clone()), *rule),
+            Logged::Text(span) => {
+                let text = Spanned::new(&src[span.range()], *span, *span);
+                (Event::Text(text), None)
+            }
+            Logged::Leaf(node) => (Event::Leaf(node.clone()), None),
+            Logged::End(span) => (Event::End(*span), None),
+        }
+    }
+}
+
+impl<T, S> Parser<T, S>
+where
+    T: Node<T> + std::fmt::Debug + Clone + PartialEq,
+    S: Clone + Default + PartialEq,
+{
+    /// Parses `src` like `parse`, keeping what `reparse` needs to update the
+    /// result after an edit
+    pub fn parse_incremental(&self, src: &str) -> IncrementalParse<T, S> {
+        self.run_incremental(src, None)
+    }
+
+    /// Parses `src`, the source of `previous` after `edit`, giving the same
+    /// result as `parse_incremental(src)`.
+    ///
+    /// The parse picks up inside the innermost node around the start of the
+    /// edit and stops as soon as it's back in step with `previous` after the
+    /// edit. Everything before and after that is copied from `previous`,
+    /// moved by the edit, without matching it again. A match can depend on
+    /// text after it though, e.g. the edit can add the closing `**` for an
+    /// earlier one, so the steps before that depended on text up to the edit
+    /// are taken again first, and the parse picks up at the first one that
+    /// comes out differently. How far a rule looked comes from `Rule::reach`,
+    /// rules that don't say are taken to have looked to the end of the node
+    /// they were tried in.
+    ///
+    /// This assumes a match after the edit doesn't depend on more than one
+    /// character before it, and that `Rule::accept_match` doesn't look at
+    /// where the nodes around a match end.
+    pub fn reparse(
+        &self,
+        previous: &IncrementalParse<T, S>,
+        src: &str,
+        edit: Edit,
+    ) -> IncrementalParse<T, S> {
+        let consistent = edit.start <= edit.old_end
+            && edit.old_end <= previous.len
+            && edit.start <= edit.new_end
+            && edit.new_end <= src.len()
+            && src.len() - edit.new_end == previous.len - edit.old_end;
+        // Counting limits depend on everything before a node, so nothing can
+        // be reused with them. Neither can anything parsed with other rules.
+        let reusable = consistent
+            && previous.rules_version == self.rules.version
+            && previous.styled.limit_exceeded.is_none()
+            && self.limits.max_nodes.is_none()
+            && self.limits.max_rule_attempts.is_none();
+        if reusable {
+            self.run_incremental(src, Some((previous, edit)))
+        } else {
+            self.run_incremental(src, None)
+        }
+    }
+
+    fn run_incremental(
+        &self,
+        src: &str,
+        previous: Option<(&IncrementalParse<T, S>, Edit)>,
+    ) -> IncrementalParse<T, S> {
+        let mut events = Events::new(self, src, S::default(), false);
+        let mut output = Output {
+            src,
+            builder: TreeBuilder::new(&self.rules, src),
+            steps: Vec::new(),
+            events: Vec::new(),
+            top_level: Vec::new(),
+        };
+
+        if let Some((previous, edit)) = previous {
+            match output.keep_steps(previous, edit, &mut events) {
+                Some((resume, checkpoint)) => {
+                    output.resume(previous, resume, &checkpoint, edit, &mut events)
+                }
+                None => events = Events::new(self, src, S::default(), false),
+            }
+        }
+
+        loop {
+            while let Some((event, rule)) = events.pop_queued() {
+                output.push(event, rule);
+            }
+
+            let checkpoint = match events.limit_exceeded() {
+                None => events.checkpoint(),
+                Some(_) => None,
+            };
+            let recorded = checkpoint.is_some();
+            if let Some(checkpoint) = checkpoint {
+                if let Some((previous, edit)) = previous {
+                    if let Some(old) = converged(previous, &checkpoint, edit, src.len()) {
+                        output.take_rest(previous, old, edit);
+                        break;
+                    }
+                }
+                output.steps.push(Step {
+                    checkpoint,
+                    events: output.events.len(),
+                    reach: 0,
+                });
+            }
+
+            let more = events
+                .step()
+                .unwrap_or_else(|_| unreachable!("only strict parses fail"));
+            if recorded {
+                output
+                    .steps
+                    .last_mut()
+                    .expect("a step was just recorded")
+                    .reach = events.reach();
+            }
+            if !more {
+                break;
+            }
+        }
+
+        IncrementalParse {
+            styled: output.builder.finish(events.limit_exceeded()),
+            steps: output.steps,
+            events: output.events,
+            top_level: output.top_level,
+            len: src.len(),
+            rules_version: self.rules.version,
+        }
+    }
+}
+
+// An `IncrementalParse` being put together
+struct Output<'a, T: Node<T>, S> {
+    src: &'a str,
+    builder: TreeBuilder<'a, T, S>,
+    steps: Vec<Step<T, S>>,
+    events: Vec<Logged<T>>,
+    top_level: Vec<usize>,
+}
+
+impl<'a, T, S> Output<'a, T, S>
+where
+    T: Node<T> + std::fmt::Debug + Clone + PartialEq,
+    S: Clone + PartialEq,
+{
+    fn push(&mut self, event: Event<T>, rule: Option<RuleId>) {
+        let logged = Logged::new(&event, rule);
+        self.push_logged(event, rule, logged);
+    }
+
+    fn replay(&mut self, logged: Logged<T>) {
+        let (event, rule) = logged.event(self.src);
+        self.push_logged(event, rule, logged);
+    }
+
+    fn push_logged(&mut self, event: Event<T>, rule: Option<RuleId>, logged: Logged<T>) {
+        if self.builder.depth() == 0 {
+            self.top_level.push(self.events.len());
+        }
+        self.events.push(logged);
+        self.builder.push(event, rule);
+    }
+
+    // Keeps the steps of `previous` before the edit that come out the same in
+    // the new source, taking again the ones that depended on text up to the
+    // edit. Returns the step to pick the parse up at, moved to the new source.
+    fn keep_steps(
+        &mut self,
+        previous: &IncrementalParse<T, S>,
+        edit: Edit,
+        events: &mut Events<T, S>,
+    ) -> Option<(usize, Checkpoint<T, S>)> {
+        let until = previous
+            .steps
+            .partition_point(|step| step.checkpoint.index < edit.start);
+        let mut stacks = MovedStacks::default();
+        for (index, step) in previous.steps[..until].iter().enumerate() {
+            let checkpoint = match step.checkpoint.moved(&edit, &mut stacks) {
+                Some(checkpoint) => checkpoint,
+                None => break,
+            };
+            if index + 1 == until {
+                return Some((index, checkpoint));
+            }
+
+            let mut reach = step.reach;
+            // Rules also see the text of the last capture
+            let affected = reach >= edit.start
+                || (step.checkpoint.last_capture).is_some_and(|span| span.end > edit.start);
+            if affected {
+                events.restore(&checkpoint);
+                events
+                    .step()
+                    .unwrap_or_else(|_| unreachable!("only strict parses fail"));
+                reach = events.reach();
+                let next = &previous.steps[index + 1].checkpoint;
+                let same = events.limit_exceeded().is_none()
+                    && (events.checkpoint()).is_some_and(|after| next.moved_to(&after, &edit));
+                if !same {
+                    return Some((index, checkpoint));
+                }
+            }
+            self.steps.push(Step {
+                checkpoint,
+                events: step.events,
+                reach,
+            });
+        }
+        self.steps.clear();
+        None
+    }
+
+    // Picks the parse up at the step `resume` of `previous`, whose steps
+    // before it were kept
+    fn resume(
+        &mut self,
+        previous: &IncrementalParse<T, S>,
+        resume: usize,
+        checkpoint: &Checkpoint<T, S>,
+        edit: Edit,
+        events: &mut Events<T, S>,
+    ) {
+        let step = &previous.steps[resume];
+        // The top level nodes finished before it stay as they are, the events
+        // of the one still open are played again
+        let started = previous
+            .top_level
+            .partition_point(|&start| start < step.events);
+        let (finished, replay_from) = if checkpoint.depth() > 0 {
+            (started - 1, previous.top_level[started - 1])
+        } else {
+            (started, step.events)
+        };
+        (self.builder.top_level_nodes).extend_from_slice(&previous.styled.nodes[..finished]);
+        self.top_level
+            .extend_from_slice(&previous.top_level[..finished]);
+        self.events
+            .extend_from_slice(&previous.events[..replay_from]);
+        for logged in &previous.events[replay_from..step.events] {
+            self.replay(
+                logged
+                    .moved(&edit)
+                    .expect("the nodes open at a checkpoint move with it"),
+            );
+        }
+        events.restore(checkpoint);
+    }
+
+    // Appends everything `previous` has from its step `old` on, moved to where
+    // it is in the new source
+    fn take_rest(&mut self, previous: &IncrementalParse<T, S>, old: usize, edit: Edit) {
+        let delta = edit.delta();
+        let from = previous.steps[old].events;
+        let offset = self.events.len() as isize - from as isize;
+        let mut stacks = MovedStacks::default();
+        for step in &previous.steps[old..] {
+            self.steps.push(Step {
+                checkpoint: (step.checkpoint.moved(&edit, &mut stacks))
+                    .expect("checkpoints past the edit move with it"),
+                events: shift(step.events, offset),
+                reach: shift(step.reach, delta),
+            });
+        }
+
+        // The nodes still open are finished by the events that close them
+        let mut next = from;
+        while self.builder.depth() > 0 {
+            self.replay(
+                previous.events[next]
+                    .moved(&edit)
+                    .expect("events past the edit move with it"),
+            );
+            next += 1;
+        }
+
+        // From there on the top level nodes are the same, only moved
+        let node = previous.top_level.partition_point(|&start| start < next);
+        let offset = self.events.len() as isize - next as isize;
+        self.top_level.extend(
+            previous.top_level[node..]
+                .iter()
+                .map(|&start| shift(start, offset)),
+        );
+        self.events
+            .extend(previous.events[next..].iter().map(|logged| {
+                logged
+                    .moved(&edit)
+                    .expect("events past the edit move with it")
+            }));
+        for node in &previous.styled.nodes[node..] {
+            let mut node = node.clone();
+            crate::node::shift_node(&mut node, delta);
+            self.builder.top_level_nodes.push(node);
+        }
+    }
+}
+
+// Finds the step of `previous` the parse at `checkpoint` is in step with, past
+// the edit, from where on the old result can be used moved
+fn converged<T, S>(
+    previous: &IncrementalParse<T, S>,
+    checkpoint: &Checkpoint<T, S>,
+    edit: Edit,
+    len: usize,
+) -> Option<usize>
+where
+    T: Node<T> + std::fmt::Debug + Clone + PartialEq,
+    S: Clone + PartialEq,
+{
+    // Rules see whether the character before a match is a newline, so that
+    // has to be past the edit too, unless nothing is left to parse
+    if checkpoint.index <= edit.new_end && checkpoint.index != len {
+        return None;
+    }
+    // Rules see the text of the last capture, and the text no rule matched
+    // becomes a node, neither can include the edit
+    let clear = |span: Span| span.end <= edit.start || span.start >= edit.new_end;
+    if !checkpoint.last_capture.is_none_or(clear)
+        || checkpoint
+            .fallback_start
+            .is_some_and(|start| start < edit.new_end)
+    {
+        return None;
+    }
+    let index = shift(checkpoint.index, -edit.delta());
+    let first = previous
+        .steps
+        .partition_point(|step| step.checkpoint.index < index);
+    previous.steps[first..]
+        .iter()
+        .take_while(|step| step.checkpoint.index == index)
+        .position(|step| step.checkpoint.moved_to(checkpoint, &edit))
+        .map(|found| first + found)
+}
+
+fn shift(index: usize, by: isize) -> usize {
+    (index as isize + by) as usize
+}
@@ -1,11 +1,29 @@
+mod error;
+pub use error::{ParseError, RuleError, RuleSetError};
+mod events;
+pub use events::{Event, Events};
+#[cfg(feature = "grammar")]
+pub mod grammar;
+mod incremental;
+pub use incremental::{Edit, IncrementalParse};
+mod limits;
+pub use limits::{Limit, ParseLimits};
+pub mod matcher;
 mod node;
 pub mod regex;
 pub use node::{MarkdownNode, Node};
 mod parse_spec;
 pub use parse_spec::ParseSpec;
 mod rule;
-pub use rule::Rule;
+pub use rule::{MatchContext, Rule};
+mod rule_set;
+pub use rule_set::RuleSet;
 mod parser;
 pub use parser::{Parser, Styled};
+mod span;
+pub use span::{Span, Spanned};
+
+mod trace;
+pub use trace::{Outcome, RuleAttempt, Trace, TraceStep};
 
 pub mod markdown_rules;
@@ -0,0 +1,46 @@
+/// Caps on how much work a single parse may do, for input that can't be
+/// trusted. Once a limit is hit the rest of the input is kept as plain text
+/// and `Styled::limit_exceeded` records which limit it was.
+///
+/// Rules that match without consuming anything, or that open a node with the
+/// exact same content as a node they opened further out, are always stopped
+/// since they would otherwise never finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseLimits {
+    /// How deeply nonterminals may be nested
+    pub max_depth: Option<usize>,
+    /// How many nodes rules may create
+    pub max_nodes: Option<usize>,
+    /// How many times rules may be run against the source
+    pub max_rule_attempts: Option<usize>,
+}
+
+impl ParseLimits {
+    pub fn new() -> ParseLimits {
+        ParseLimits::default()
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> ParseLimits {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn max_nodes(mut self, max_nodes: usize) -> ParseLimits {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    pub fn max_rule_attempts(mut self, max_rule_attempts: usize) -> ParseLimits {
+        self.max_rule_attempts = Some(max_rule_attempts);
+        self
+    }
+}
+
+/// The limit that stopped a parse early
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Limit {
+    Depth,
+    Nodes,
+    RuleAttempts,
+    NoProgress,
+}
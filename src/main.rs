@@ -1,28 +1,9 @@
-use simple_ast::{MarkdownNode, Parser, Rule};
+use simple_ast::{MarkdownNode, Parser};
 
-use simple_ast::markdown_rules::*;
+use simple_ast::markdown_rules::message_content;
 
 fn main() {
-    let rules: &[&dyn Rule<MarkdownNode>] = &[
-        &Escape,
-        &Newline,
-        &Emoji,
-        &Timestamp,
-        &ChannelMention,
-        &UserMention,
-        &RoleMention,
-        &Bold,
-        &Underline,
-        &Italic,
-        &Strikethrough,
-        &Spoiler,
-        &BlockQuote::new(),
-        &Code,
-        &InlineCode,
-        &Text,
-    ];
-
-    let parser = Parser::with_rules(rules);
+    let parser: Parser<MarkdownNode> = Parser::with_rule_set(message_content());
     let i = "_fooff_ **bar _foo_**";
     let result = parser.parse(i);
     println!("\nResult:\n{:#?}", result);
@@ -1,7 +1,8 @@
-use crate::regex::{Captures, Regex};
-use crate::{MarkdownNode, ParseSpec, Rule};
+use crate::regex::{Captures, LimitExceeded, Regex, RegexEngine, RegexOptions};
+use crate::{MarkdownNode, MatchContext, ParseSpec, Rule, RuleError, RuleSet, Spanned};
 use lazy_static::lazy_static;
-use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::str::FromStr;
 
 macro_rules! styles {
     ( $( $style:ident ),* $(,)? ) => {
@@ -27,31 +28,61 @@ styles! {
     UserMention,
     RoleMention,
     Timestamp,
+    BlockQuote,
+    MaskedLink,
 }
 
-pub struct BlockQuote {
-    in_quote: RefCell<bool>,
+// Enough for any message, but stops patterns that backtrack badly on hostile
+// input from holding up the parse
+const BACKTRACK_LIMIT: u32 = 1_000_000;
+
+// The regex behind each rule. The rules themselves match with `DefaultEngine`
+// regexes compiled once, `OnEngine` compiles the pattern with another engine.
+// Patterns start with `\G` rather than `^`, so they can be matched at an
+// offset in the whole source.
+trait Pattern {
+    const PATTERN: &'static str;
+
+    fn options() -> RegexOptions {
+        RegexOptions::new()
+    }
 }
 
-impl BlockQuote {
-    pub fn new() -> BlockQuote {
-        BlockQuote {
-            in_quote: RefCell::new(false),
+macro_rules! patterns {
+    ( $( $style:ident => $regex:ident: $pattern:expr $(, $options:expr )?; )* ) => {
+        $(
+            impl Pattern for $style {
+                const PATTERN: &'static str = $pattern;
+                $(
+                    fn options() -> RegexOptions {
+                        $options
+                    }
+                )?
+            }
+        )*
+
+        lazy_static! {
+            $(
+                static ref $regex: Regex = Regex::with_options($style::PATTERN, $style::options()).unwrap();
+            )*
         }
-    }
+    };
 }
 
-lazy_static! {
-    static ref ESCAPE: Regex = Regex::new(r"^\\([^0-9A-Za-z\s])").unwrap();
-    static ref NEWLINE: Regex = Regex::new(r"^(?:\n *)*\n").unwrap();
-    static ref BOLD: Regex =
-        Regex::new(r"^\*\*([\s\S]+?)\*\*(?!\*)").unwrap();
-    static ref UNDERLINE: Regex = Regex::new(r"^__([\s\S]+?)__(?!_)").unwrap();
-    static ref ITALICS: Regex = Regex::new(concat!(
-        "^\\b_", "((?:__|\\\\[\\s\\S]|[^\\\\_])+?)_", "\\b",
+patterns! {
+    Escape => ESCAPE: r"\G\\(?<char>[^0-9A-Za-z\s])";
+    Newline => NEWLINE: r"\G(?:\n *)*\n";
+    Bold => BOLD: r"\G\*\*(?<content>[\s\S]+?)\*\*(?!\*)";
+    Underline => UNDERLINE: r"\G__(?<content>[\s\S]+?)__(?!_)";
+    Italic => ITALICS: concat!(
+        // _s that aren't inside a word. Like `\b`, but spelling out what a
+        // word character is, since engines don't agree on it
+        "\\G(?<![\\p{L}\\p{M}\\p{N}\\p{Pc}])_",
+        "(?<underscored>(?:__|\\\\[\\s\\S]|[^\\\\_])+?)",
+        "_(?![\\p{L}\\p{M}\\p{N}\\p{Pc}])",
         "|",
         // Or match *s that are followed by a non-space:
-        "^\\*(?=\\S)(",
+        "\\G\\*(?=\\S)(?<starred>",
         // Match any of:
         //  - `**`: so that bolds inside italics don't close the
         // italics
@@ -60,31 +91,304 @@ lazy_static! {
         "(?:\\*\\*|\\s+(?:[^*\\s]|\\*\\*)|[^\\s*])+?",
         // followed by a non-space, non-* then *
         ")\\*(?!\\*)"
-    )).unwrap();
-    static ref STRIKETHROUGH: Regex = Regex::new(r"^~~([\s\S]+?)~~(?!_)").unwrap();
-    static ref TEXT: Regex =
-        Regex::new(r"^[\s\S]+?(?=[^0-9A-Za-z\s\x{00c0}-\x{ffff}]|\n| {2,}\n|\w+:\S|$)")
-            .unwrap();
+    ), RegexOptions::new().backtrack_limit(BACKTRACK_LIMIT);
+    Strikethrough => STRIKETHROUGH: r"\G~~(?<content>[\s\S]+?)~~(?!_)";
+    Text => TEXT:
+        r"\G[\s\S]+?(?=[^0-9A-Za-z\s\x{00c0}-\x{ffff}]|\n| {2,}\n|[\p{L}\p{M}\p{N}\p{Pc}]+:\S|$)",
+        RegexOptions::new().backtrack_limit(BACKTRACK_LIMIT);
 
     // Additional Discord rules
-    static ref INLINE_CODE: Regex = Regex::new(r"^(`+)(\s*([\s\S]*?[^`])\s*)\1(?!`)").unwrap();
-    static ref CODE: Regex = Regex::new(
-        r"^```((([A-z0-9-]+?)\n+)?\n*([\S\s]+?)\n*)```",
-    ).unwrap();
-    static ref SPOILER: Regex = Regex::new(r"^\|\|([\s\S]+?)\|\|").unwrap();
-    static ref BLOCK_QUOTE: Regex = Regex::new(r"^( *>>> +([\s\S]*))|^( *>(?!>>) +([^\n]*(\n *>(?!>>) +[^\n]*)*\n?))").unwrap();
-    static ref CHANNEL_MENTION: Regex = Regex::new(r"^<#(\d+?)>").unwrap();
-    static ref ROLE_MENTION: Regex = Regex::new(r"^<@&(\d+?)>").unwrap();
-    static ref EMOJI: Regex = Regex::new(r"^<a?:(.+?):(\d+?)>").unwrap();
-    static ref USER_MENTION: Regex = Regex::new(r"^<@!?(\d+?)>").unwrap();
-    static ref TIMESTAMP: Regex = Regex::new(r"^<t:(-?\d{1,17})(?::(t|T|d|D|f|F|R))?>").unwrap();
-}
-
-impl Rule<MarkdownNode> for Escape {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        let text = captures.at(1).unwrap();
-        ParseSpec::create_terminal(Some(MarkdownNode::Text(text.to_owned())), start, end)
+    InlineCode => INLINE_CODE: r"\G(?<ticks>`+)(?<content>\s*(?<code>[\s\S]*?[^`])\s*)\k<ticks>(?!`)";
+    Code => CODE: r"\G```(?<content>(?:(?<language>[A-z0-9-]+?)\n+)?\n*(?<code>[\S\s]+?)\n*)```";
+    Spoiler => SPOILER: r"\G\|\|(?<content>[\s\S]+?)\|\|";
+    BlockQuote => BLOCK_QUOTE: r"\G(?: *>>> +(?<multi_line>[\s\S]*))|\G(?: *>(?!>>) +(?<single_line>[^\n]*(?:\n *>(?!>>) +[^\n]*)*\n?))";
+    ChannelMention => CHANNEL_MENTION: r"\G<#(?<id>\d+?)>";
+    RoleMention => ROLE_MENTION: r"\G<@&(?<id>\d+?)>";
+    Emoji => EMOJI: r"\G<a?:(?<name>.+?):(?<id>\d+?)>";
+    UserMention => USER_MENTION: r"\G<@!?(?<id>\d+?)>";
+    MaskedLink => MASKED_LINK:
+        r"\G\[(?<text>(?:\\[\s\S]|[^\\\[\]])+)\]\(\s*<?(?<url>https?://[^\s<>()]+)>?\s*\)";
+    Timestamp => TIMESTAMP: r"\G<t:(?<time>-?\d{1,17})(?::(?<style>t|T|d|D|f|F|R))?>";
+}
+
+/// The rules for message content, in an order that parses messages the way
+/// Discord does:
+///
+/// - `escape` first, so escaped characters never start anything else
+/// - the `<...>` rules (`emoji`, `timestamp` and the mentions) before any
+///   styles, their contents aren't markdown
+/// - `bold` before `italic` and `underline` before `italic`, otherwise `**`
+///   and `__` would be read as two italics
+/// - `block_quote`, `code` and `inline_code` after the styles, `code` before
+///   `inline_code` since both start with a backtick
+/// - `text` last, it matches anything the other rules didn't
+pub fn message_content<S>() -> RuleSet<MarkdownNode, S> {
+    content::<Builtin, S>(false)
+}
+
+/// The rules for embed descriptions, message content plus masked links
+pub fn embed_description<S>() -> RuleSet<MarkdownNode, S> {
+    content::<Builtin, S>(true)
+}
+
+/// The rules for embed field values, which are the same as for descriptions
+pub fn embed_field_value<S>() -> RuleSet<MarkdownNode, S> {
+    content::<Builtin, S>(true)
+}
+
+/// The rules for embed titles: only inline styles, no mentions, quotes or
+/// code blocks
+pub fn embed_title<S>() -> RuleSet<MarkdownNode, S> {
+    inline_styles::<Builtin, S>()
+}
+
+/// The rules for usernames and nicknames, which are always shown as they are
+pub fn username<S>() -> RuleSet<MarkdownNode, S> {
+    username_rules::<Builtin, S>()
+}
+
+/// Like `message_content`, with the patterns compiled by the engine `E`.
+/// Unlike the built in rules, which compile theirs once, this compiles them
+/// every time. Panics if `E` can't compile one of them.
+pub fn message_content_with<E: RegexEngine, S>() -> RuleSet<MarkdownNode, S> {
+    content::<Engine<E>, S>(false)
+}
+
+/// Like `embed_description`, see `message_content_with`
+pub fn embed_description_with<E: RegexEngine, S>() -> RuleSet<MarkdownNode, S> {
+    content::<Engine<E>, S>(true)
+}
+
+/// Like `embed_field_value`, see `message_content_with`
+pub fn embed_field_value_with<E: RegexEngine, S>() -> RuleSet<MarkdownNode, S> {
+    content::<Engine<E>, S>(true)
+}
+
+/// Like `embed_title`, see `message_content_with`
+pub fn embed_title_with<E: RegexEngine, S>() -> RuleSet<MarkdownNode, S> {
+    inline_styles::<Engine<E>, S>()
+}
+
+/// Like `username`, see `message_content_with`
+pub fn username_with<E: RegexEngine, S>() -> RuleSet<MarkdownNode, S> {
+    username_rules::<Engine<E>, S>()
+}
+
+// Link text is parsed with the "masked_link_text" rule set, which only has
+// inline styles
+fn content<A: AddRule<S>, S>(masked_links: bool) -> RuleSet<MarkdownNode, S> {
+    let mut rules = RuleSet::new();
+    A::add(&mut rules, "escape", Escape);
+    A::add(&mut rules, "newline", Newline);
+    A::add(&mut rules, "emoji", Emoji);
+    A::add(&mut rules, "timestamp", Timestamp);
+    A::add(&mut rules, "channel_mention", ChannelMention);
+    A::add(&mut rules, "user_mention", UserMention);
+    A::add(&mut rules, "role_mention", RoleMention);
+    if masked_links {
+        A::add(&mut rules, "masked_link", MaskedLink);
+    }
+    A::add(&mut rules, "bold", Bold);
+    A::add(&mut rules, "underline", Underline);
+    A::add(&mut rules, "italic", Italic);
+    A::add(&mut rules, "strikethrough", Strikethrough);
+    A::add(&mut rules, "spoiler", Spoiler);
+    A::add(&mut rules, "block_quote", BlockQuote);
+    A::add(&mut rules, "code", Code);
+    A::add(&mut rules, "inline_code", InlineCode);
+    A::add(&mut rules, "text", Text);
+    if masked_links {
+        rules.with_nested("masked_link_text", inline_styles::<A, S>())
+    } else {
+        rules
+    }
+}
+
+fn inline_styles<A: AddRule<S>, S>() -> RuleSet<MarkdownNode, S> {
+    let mut rules = RuleSet::new();
+    A::add(&mut rules, "escape", Escape);
+    A::add(&mut rules, "newline", Newline);
+    A::add(&mut rules, "emoji", Emoji);
+    A::add(&mut rules, "bold", Bold);
+    A::add(&mut rules, "underline", Underline);
+    A::add(&mut rules, "italic", Italic);
+    A::add(&mut rules, "strikethrough", Strikethrough);
+    A::add(&mut rules, "spoiler", Spoiler);
+    A::add(&mut rules, "inline_code", InlineCode);
+    A::add(&mut rules, "text", Text);
+    rules
+}
+
+fn username_rules<A: AddRule<S>, S>() -> RuleSet<MarkdownNode, S> {
+    let mut rules = RuleSet::new();
+    A::add(&mut rules, "text", Text);
+    rules
+}
+
+// How the rule set functions add a rule: as it is, or on another engine
+trait AddRule<S> {
+    fn add<R>(rules: &mut RuleSet<MarkdownNode, S>, name: &str, rule: R)
+    where
+        R: Pattern + Rule<MarkdownNode, S> + 'static;
+}
+
+struct Builtin;
+
+struct Engine<E>(PhantomData<E>);
+
+impl<S> AddRule<S> for Builtin {
+    fn add<R>(rules: &mut RuleSet<MarkdownNode, S>, name: &str, rule: R)
+    where
+        R: Pattern + Rule<MarkdownNode, S> + 'static,
+    {
+        rules.push(name, rule).expect("rule names are unique");
+    }
+}
+
+impl<E: RegexEngine, S> AddRule<S> for Engine<E> {
+    fn add<R>(rules: &mut RuleSet<MarkdownNode, S>, name: &str, rule: R)
+    where
+        R: Pattern + Rule<MarkdownNode, S> + 'static,
+    {
+        let regex = Regex::<E>::compile_with_options(R::PATTERN, R::options())
+            .unwrap_or_else(|error| panic!("can't compile the {} pattern: {}", name, error));
+        rules
+            .push(name, OnEngine { rule, regex })
+            .expect("rule names are unique");
+    }
+}
+
+// A rule here, matching with its pattern compiled by `E` instead
+struct OnEngine<R, E: RegexEngine> {
+    rule: R,
+    regex: Regex<E>,
+}
+
+impl<R, E, S> Rule<MarkdownNode, S> for OnEngine<R, E>
+where
+    R: Rule<MarkdownNode, S>,
+    E: RegexEngine,
+{
+    fn name(&self) -> &str {
+        Rule::<MarkdownNode, S>::name(&self.rule)
+    }
+
+    fn accept_match(&self, context: &MatchContext<MarkdownNode>, state: &S) -> bool {
+        self.rule.accept_match(context, state)
+    }
+
+    fn allows_nesting(&self) -> bool {
+        Rule::<MarkdownNode, S>::allows_nesting(&self.rule)
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Rule::<MarkdownNode, S>::first_bytes(&self.rule)
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        Rule::<MarkdownNode, S>::reach(&self.rule, src, start, match_end, end)
+    }
+
+    fn parse(
+        &self,
+        captures: &Captures,
+        state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        self.rule.parse(captures, state)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        self.regex.captures(src)
+    }
+
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        self.regex.captures_at(src, start, end)
+    }
+
+    fn finish(&self, node: &mut Spanned<MarkdownNode>, src: &str) {
+        Rule::<MarkdownNode, S>::finish(&self.rule, node, src)
+    }
+}
+
+// Where the character after `index` ends, for patterns that look one
+// character past their match
+fn next_char(src: &str, index: usize, end: usize) -> usize {
+    src[index..end]
+        .chars()
+        .next()
+        .map_or(end, |next| index + next.len_utf8())
+}
+
+// Where the character after the next word ends, for patterns that look past
+// whitespace and the word after it
+fn next_word(src: &str, index: usize, end: usize) -> usize {
+    let word = src[index..end].trim_start();
+    let word_start = end - word.len();
+    let word_end = word
+        .find(char::is_whitespace)
+        .map_or(end, |len| word_start + len);
+    next_char(src, word_end, end)
+}
+
+// Where the line `index` is in ends, past its newline, for patterns that
+// don't match one
+fn line_end(src: &str, index: usize, end: usize) -> usize {
+    src[index..end]
+        .find('\n')
+        .map_or(end, |len| index + len + 1)
+}
+
+// How far a pattern matching `opening`, content and a closing delimiter with
+// one character of lookahead looked. Without the opening it fails within it,
+// with it the closing delimiter could be anywhere.
+fn delimited(
+    src: &str,
+    start: usize,
+    match_end: Option<usize>,
+    end: usize,
+    opening: &str,
+) -> usize {
+    match match_end {
+        Some(match_end) => next_char(src, match_end, end),
+        None if src[start..end].starts_with(opening) => end,
+        None => (start + opening.len()).min(end),
+    }
+}
+
+fn parse_number<N: FromStr>(text: &str) -> Result<N, RuleError>
+where
+    N::Err: std::fmt::Display,
+{
+    text.parse()
+        .map_err(|err| RuleError::Invalid(format!("invalid number {:?}: {}", text, err)))
+}
+
+impl<S> Rule<MarkdownNode, S> for Escape {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("char")?;
+        let text = captures.try_name("char")?;
+        Ok(ParseSpec::create_terminal(
+            Some(MarkdownNode::Text(text.to_owned())),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"\\")
+    }
+
+    fn reach(&self, src: &str, start: usize, _match_end: Option<usize>, end: usize) -> usize {
+        next_char(src, next_char(src, start, end), end)
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -92,10 +396,29 @@ impl Rule<MarkdownNode> for Escape {
     }
 }
 
-impl Rule<MarkdownNode> for Newline {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(0).unwrap();
-        ParseSpec::create_terminal(Some(MarkdownNode::Text("\n".to_owned())), start, end)
+impl<S> Rule<MarkdownNode, S> for Newline {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_pos(0)?;
+        Ok(ParseSpec::create_terminal(
+            Some(MarkdownNode::Text("\n".to_owned())),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"\n")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match match_end {
+            Some(match_end) => next_word(src, match_end, end),
+            None => next_char(src, start, end),
+        }
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -103,10 +426,26 @@ impl Rule<MarkdownNode> for Newline {
     }
 }
 
-impl Rule<MarkdownNode> for Bold {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        ParseSpec::create_nonterminal(Some(MarkdownNode::Bold(Vec::new())), start, end)
+impl<S> Rule<MarkdownNode, S> for Bold {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("content")?;
+        Ok(ParseSpec::create_nonterminal(
+            Some(MarkdownNode::Bold(Vec::new())),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"*")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        delimited(src, start, match_end, end, "**")
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -114,59 +453,185 @@ impl Rule<MarkdownNode> for Bold {
     }
 }
 
-impl Rule<MarkdownNode> for Underline {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        ParseSpec::create_nonterminal(Some(MarkdownNode::Underline(vec![])), start, end)
+impl<S> Rule<MarkdownNode, S> for Underline {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("content")?;
+        Ok(ParseSpec::create_nonterminal(
+            Some(MarkdownNode::Underline(vec![])),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"_")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        delimited(src, start, match_end, end, "__")
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
-        UNDERLINE.captures(&src)
+        UNDERLINE.captures(src)
     }
 }
 
-impl Rule<MarkdownNode> for Italic {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = match captures.pos(1) {
+impl<S> Rule<MarkdownNode, S> for Italic {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = match captures.name_pos("underscored") {
             Some(pos) => pos,
-            None => captures.pos(2).unwrap(),
+            None => captures.try_name_pos("starred")?,
         };
-        ParseSpec::create_nonterminal(Some(MarkdownNode::Italic(vec![])), start, end)
+        Ok(ParseSpec::create_nonterminal(
+            Some(MarkdownNode::Italic(vec![])),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"_*")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        if let Some(match_end) = match_end {
+            return next_char(src, match_end, end);
+        }
+        // An `_` inside a word or a `*` before a space fails right away
+        let second = next_char(src, start, end);
+        let fails_at_once = match src[start..end].chars().next() {
+            Some('_') => (src[..start].chars().next_back())
+                .is_some_and(|before| before.is_ascii_alphanumeric() || before == '_'),
+            Some('*') => (src[second..end].chars().next()).is_none_or(char::is_whitespace),
+            _ => true,
+        };
+        if fails_at_once {
+            next_char(src, second, end)
+        } else {
+            end
+        }
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
-        ITALICS.captures(&src)
+        ITALICS.captures(src)
+    }
+
+    // Has to see the char before the match, e.g. in snake_case_words
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        ITALICS.captures_at(src, start, end)
     }
 }
 
-impl Rule<MarkdownNode> for Strikethrough {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        ParseSpec::create_nonterminal(Some(MarkdownNode::Strikethrough(vec![])), start, end)
+impl<S> Rule<MarkdownNode, S> for Strikethrough {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("content")?;
+        Ok(ParseSpec::create_nonterminal(
+            Some(MarkdownNode::Strikethrough(vec![])),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"~")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        delimited(src, start, match_end, end, "~~")
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
-        STRIKETHROUGH.captures(&src)
+        STRIKETHROUGH.captures(src)
     }
 }
 
-impl Rule<MarkdownNode> for Text {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(0).unwrap();
-        let text = captures.at(0).unwrap();
-        ParseSpec::create_terminal(Some(MarkdownNode::Text(text.to_owned())), start, end)
+impl<S> Rule<MarkdownNode, S> for Text {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_pos(0)?;
+        let text = captures.try_at(0)?;
+        Ok(ParseSpec::create_terminal(
+            Some(MarkdownNode::Text(text.to_owned())),
+            start,
+            end,
+        ))
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
         TEXT.captures(src)
     }
+
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        TEXT.captures_at(src, start, end)
+    }
+
+    fn reach(&self, src: &str, _start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match_end.map_or(end, |match_end| next_word(src, match_end, end))
+    }
 }
 
-impl Rule<MarkdownNode> for InlineCode {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(2).unwrap();
-        let text = captures.at(3).unwrap();
-        ParseSpec::create_terminal(Some(MarkdownNode::InlineCode(text.to_owned())), start, end)
+impl<S> Rule<MarkdownNode, S> for InlineCode {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("content")?;
+        let text = captures.try_name("code")?;
+        Ok(ParseSpec::create_terminal(
+            Some(MarkdownNode::InlineCode(text.to_owned())),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"`")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        // The pattern looks for a match with all the ticks it starts with and
+        // the spaces after them first, one with fewer was found after that
+        // failed
+        let match_end = match match_end {
+            Some(match_end) => match_end,
+            None => return end,
+        };
+        let after_ticks = src[start..end].trim_start_matches('`');
+        let before_ticks = src[start..match_end].trim_end_matches('`');
+        let opening = end - start - after_ticks.len();
+        let closing = match_end - start - before_ticks.len();
+        let first_try = opening == closing && !after_ticks.starts_with(char::is_whitespace);
+        if first_try {
+            next_char(src, match_end, end)
+        } else {
+            end
+        }
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -174,16 +639,34 @@ impl Rule<MarkdownNode> for InlineCode {
     }
 }
 
-impl Rule<MarkdownNode> for Code {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        let language = captures.at(3).unwrap_or("");
-        let text = captures.at(4).unwrap();
-        ParseSpec::create_terminal(
+impl<S> Rule<MarkdownNode, S> for Code {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("content")?;
+        let language = captures.name("language").unwrap_or("");
+        let text = captures.try_name("code")?;
+        Ok(ParseSpec::create_terminal(
             Some(MarkdownNode::Code(language.to_owned(), text.to_owned())),
             start,
             end,
-        )
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"`")
+    }
+
+    fn reach(&self, src: &str, start: usize, _match_end: Option<usize>, end: usize) -> usize {
+        // Without a language the pattern could have looked for one anywhere
+        // up to `end`
+        if src[start..end].starts_with("```") {
+            end
+        } else {
+            (start + 3).min(end)
+        }
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -191,10 +674,26 @@ impl Rule<MarkdownNode> for Code {
     }
 }
 
-impl Rule<MarkdownNode> for Spoiler {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        ParseSpec::create_nonterminal(Some(MarkdownNode::Spoiler(Vec::new())), start, end)
+impl<S> Rule<MarkdownNode, S> for Spoiler {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("content")?;
+        Ok(ParseSpec::create_nonterminal(
+            Some(MarkdownNode::Spoiler(Vec::new())),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"|")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        delimited(src, start, match_end, end, "||")
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -202,55 +701,106 @@ impl Rule<MarkdownNode> for Spoiler {
     }
 }
 
-impl Rule<MarkdownNode> for BlockQuote {
-    fn accept_match(&self, last_capture: Option<&str>) -> bool {
-        match last_capture {
-            Some(last_capture) => {
-                if last_capture.ends_with('\n') {
-                    !*self.in_quote.borrow()
-                } else {
-                    false
-                }
-            }
-            None => true,
-        }
+impl<S> Rule<MarkdownNode, S> for BlockQuote {
+    fn accept_match(&self, context: &MatchContext<MarkdownNode>, _state: &S) -> bool {
+        context.at_line_start
+    }
+
+    // Quotes can't contain other quotes
+    fn allows_nesting(&self) -> bool {
+        false
     }
 
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        // group 2 for >>> and group 3 for >
-        *self.in_quote.borrow_mut() = true;
-        let single_line = captures.pos(2).is_none();
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let single_line = captures.name_pos("multi_line").is_none();
         if single_line {
-            // group 4 excludes the leading >, which prevents infinite loops
-            let (start, end) = captures.pos(4).unwrap();
+            // The content excludes the leading >, which prevents infinite loops
+            let (start, end) = captures.try_name_pos("single_line")?;
 
-            ParseSpec::create_nonterminal(
+            Ok(ParseSpec::create_nonterminal(
                 Some(MarkdownNode::SingleBlockQuote(Vec::new())),
                 start,
                 end,
-            )
+            ))
         } else {
-            let (start, end) = captures.pos(2).unwrap();
+            let (start, end) = captures.try_name_pos("multi_line")?;
 
-            ParseSpec::create_nonterminal(Some(MarkdownNode::BlockQuote(Vec::new())), start, end)
+            Ok(ParseSpec::create_nonterminal(
+                Some(MarkdownNode::BlockQuote(Vec::new())),
+                start,
+                end,
+            ))
         }
     }
 
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b" >")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        next_word(src, match_end.unwrap_or(start), end)
+    }
+
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
         BLOCK_QUOTE.captures(src)
     }
 }
 
-impl Rule<MarkdownNode> for UserMention {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        ParseSpec::create_terminal(
-            Some(MarkdownNode::UserMention(
-                captures.at(1).and_then(|id| id.parse().ok()).unwrap(),
-            )),
+impl<S> Rule<MarkdownNode, S> for MaskedLink {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("text")?;
+        let url = captures.try_name("url")?;
+        Ok(ParseSpec::create_nonterminal(
+            Some(MarkdownNode::MaskedLink(Vec::new(), url.to_owned())),
             start,
             end,
         )
+        .with_rule_set("masked_link_text"))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"[")
+    }
+
+    fn reach(&self, _src: &str, _start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match_end.unwrap_or(end)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        MASKED_LINK.captures(src)
+    }
+}
+
+impl<S> Rule<MarkdownNode, S> for UserMention {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("id")?;
+        Ok(ParseSpec::create_terminal(
+            Some(MarkdownNode::UserMention(parse_number(
+                captures.try_name("id")?,
+            )?)),
+            start,
+            end,
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"<")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match_end.unwrap_or_else(|| line_end(src, start, end))
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -258,16 +808,28 @@ impl Rule<MarkdownNode> for UserMention {
     }
 }
 
-impl Rule<MarkdownNode> for ChannelMention {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        ParseSpec::create_terminal(
-            Some(MarkdownNode::ChannelMention(
-                captures.at(1).and_then(|id| id.parse().ok()).unwrap(),
-            )),
+impl<S> Rule<MarkdownNode, S> for ChannelMention {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("id")?;
+        Ok(ParseSpec::create_terminal(
+            Some(MarkdownNode::ChannelMention(parse_number(
+                captures.try_name("id")?,
+            )?)),
             start,
             end,
-        )
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"<")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match_end.unwrap_or_else(|| line_end(src, start, end))
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -275,16 +837,28 @@ impl Rule<MarkdownNode> for ChannelMention {
     }
 }
 
-impl Rule<MarkdownNode> for RoleMention {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(1).unwrap();
-        ParseSpec::create_terminal(
-            Some(MarkdownNode::RoleMention(
-                captures.at(1).and_then(|id| id.parse().ok()).unwrap(),
-            )),
+impl<S> Rule<MarkdownNode, S> for RoleMention {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_name_pos("id")?;
+        Ok(ParseSpec::create_terminal(
+            Some(MarkdownNode::RoleMention(parse_number(
+                captures.try_name("id")?,
+            )?)),
             start,
             end,
-        )
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"<")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match_end.unwrap_or_else(|| line_end(src, start, end))
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -292,18 +866,30 @@ impl Rule<MarkdownNode> for RoleMention {
     }
 }
 
-impl Rule<MarkdownNode> for Emoji {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, _) = captures.pos(1).unwrap();
-        let (_, end) = captures.pos(2).unwrap();
-        ParseSpec::create_terminal(
+impl<S> Rule<MarkdownNode, S> for Emoji {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, _) = captures.try_name_pos("name")?;
+        let (_, end) = captures.try_name_pos("id")?;
+        Ok(ParseSpec::create_terminal(
             Some(MarkdownNode::Emoji(
-                captures.at(1).unwrap().to_owned(),
-                captures.at(2).and_then(|id| id.parse().ok()).unwrap(),
+                captures.try_name("name")?.to_owned(),
+                parse_number(captures.try_name("id")?)?,
             )),
             start,
             end,
-        )
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"<")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match_end.unwrap_or_else(|| line_end(src, start, end))
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
@@ -311,22 +897,32 @@ impl Rule<MarkdownNode> for Emoji {
     }
 }
 
-
-impl Rule<MarkdownNode> for Timestamp {
-    fn parse(&self, captures: &Captures) -> ParseSpec<MarkdownNode> {
-        let (start, end) = captures.pos(0).unwrap();
-        ParseSpec::create_terminal(
+impl<S> Rule<MarkdownNode, S> for Timestamp {
+    fn parse(
+        &self,
+        captures: &Captures,
+        _state: &mut S,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let (start, end) = captures.try_pos(0)?;
+        Ok(ParseSpec::create_terminal(
             Some(MarkdownNode::Timestamp(
-                captures.at(1).unwrap().to_owned().parse().unwrap(),
-                captures.at(2).and_then(|c| c.chars().next()),
+                parse_number(captures.try_name("time")?)?,
+                captures.name("style").and_then(|c| c.chars().next()),
             )),
             start,
             end,
-        )
+        ))
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"<")
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        match_end.unwrap_or_else(|| line_end(src, start, end))
     }
 
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
         TIMESTAMP.captures(src)
     }
 }
-
@@ -0,0 +1,323 @@
+//! Rules written as plain Rust matchers instead of regexes.
+//!
+//! Matchers don't backtrack: each part of a sequence takes the first match it
+//! finds, and an alternation takes its first option that matches.
+
+use crate::regex::{Captures, LimitExceeded};
+use crate::{Node, ParseSpec, Rule, RuleError};
+
+/// The capture groups found so far, as byte ranges of the matched text
+pub type Groups = Vec<Option<(usize, usize)>>;
+
+pub trait Matcher: Send + Sync {
+    /// Tries to match `src` starting at `start`, returning where the match
+    /// ends. Capture groups are pushed onto `groups` in the order they open.
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize>;
+
+    /// The bytes a match can start with, `None` if it can start with anything
+    /// or be empty
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Matches the start of `src`, group 0 being the whole match
+    fn captures<'t>(&self, src: &'t str) -> Option<Captures<'t>> {
+        let mut groups = vec![None];
+        let end = self.match_at(src, 0, &mut groups)?;
+        groups[0] = Some((0, end));
+        Some(Captures::from_groups(src, groups))
+    }
+
+    /// Matches at `start` in `src[..end]`, with positions indexing `src`
+    fn captures_at<'t>(&self, src: &'t str, start: usize, end: usize) -> Option<Captures<'t>> {
+        let src = &src[..end];
+        let mut groups = vec![None];
+        let end = self.match_at(src, start, &mut groups)?;
+        groups[0] = Some((start, end));
+        Some(Captures::from_groups(src, groups))
+    }
+
+    /// Matches `self` and then `next` right after it
+    fn then<M: Matcher>(self, next: M) -> Then<Self, M>
+    where
+        Self: Sized,
+    {
+        Then(self, next)
+    }
+
+    /// Matches `self`, or `other` if `self` doesn't match
+    fn or<M: Matcher>(self, other: M) -> Or<Self, M>
+    where
+        Self: Sized,
+    {
+        Or(self, other)
+    }
+}
+
+impl<M: Matcher + ?Sized> Matcher for Box<M> {
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize> {
+        (**self).match_at(src, start, groups)
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        (**self).first_bytes()
+    }
+}
+
+pub struct Literal(String);
+
+/// Matches exactly `text`
+pub fn literal(text: impl Into<String>) -> Literal {
+    Literal(text.into())
+}
+
+impl Matcher for Literal {
+    fn match_at(&self, src: &str, start: usize, _groups: &mut Groups) -> Option<usize> {
+        if src[start..].starts_with(&self.0) {
+            Some(start + self.0.len())
+        } else {
+            None
+        }
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        self.0.bytes().next().map(|byte| vec![byte])
+    }
+}
+
+pub struct Function<F>(F);
+
+/// Matches with a closure, which gets the rest of the source and returns the
+/// length of its match. The length must end on a char boundary.
+pub fn function<F>(f: F) -> Function<F>
+where
+    F: Fn(&str) -> Option<usize> + Send + Sync,
+{
+    Function(f)
+}
+
+impl<F> Matcher for Function<F>
+where
+    F: Fn(&str) -> Option<usize> + Send + Sync,
+{
+    fn match_at(&self, src: &str, start: usize, _groups: &mut Groups) -> Option<usize> {
+        let len = (self.0)(&src[start..])?;
+        let end = start + len;
+        if src.is_char_boundary(end) {
+            Some(end)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct Capture<M>(M);
+
+/// Records what `matcher` matched as a capture group
+pub fn capture<M: Matcher>(matcher: M) -> Capture<M> {
+    Capture(matcher)
+}
+
+impl<M: Matcher> Matcher for Capture<M> {
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize> {
+        let group = groups.len();
+        groups.push(None);
+        match self.0.match_at(src, start, groups) {
+            Some(end) => {
+                groups[group] = Some((start, end));
+                Some(end)
+            }
+            None => {
+                groups.truncate(group);
+                None
+            }
+        }
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        self.0.first_bytes()
+    }
+}
+
+pub struct Then<A, B>(A, B);
+
+impl<A: Matcher, B: Matcher> Matcher for Then<A, B> {
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize> {
+        let group = groups.len();
+        let middle = self.0.match_at(src, start, groups)?;
+        match self.1.match_at(src, middle, groups) {
+            Some(end) => Some(end),
+            None => {
+                groups.truncate(group);
+                None
+            }
+        }
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        // Only matchers that never match nothing report first bytes, so the
+        // first part decides
+        self.0.first_bytes()
+    }
+}
+
+pub struct Or<A, B>(A, B);
+
+impl<A: Matcher, B: Matcher> Matcher for Or<A, B> {
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize> {
+        self.0
+            .match_at(src, start, groups)
+            .or_else(|| self.1.match_at(src, start, groups))
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        let mut bytes = self.0.first_bytes()?;
+        bytes.extend(self.1.first_bytes()?);
+        Some(bytes)
+    }
+}
+
+/// Matches each of `matchers` in turn
+pub fn sequence(matchers: Vec<Box<dyn Matcher>>) -> Sequence {
+    Sequence(matchers)
+}
+
+pub struct Sequence(Vec<Box<dyn Matcher>>);
+
+impl Matcher for Sequence {
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize> {
+        let group = groups.len();
+        let mut end = start;
+        for matcher in &self.0 {
+            match matcher.match_at(src, end, groups) {
+                Some(next) => end = next,
+                None => {
+                    groups.truncate(group);
+                    return None;
+                }
+            }
+        }
+        Some(end)
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        self.0.first()?.first_bytes()
+    }
+}
+
+/// Matches the first of `matchers` that matches
+pub fn alternation(matchers: Vec<Box<dyn Matcher>>) -> Alternation {
+    Alternation(matchers)
+}
+
+pub struct Alternation(Vec<Box<dyn Matcher>>);
+
+impl Matcher for Alternation {
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize> {
+        self.0
+            .iter()
+            .find_map(|matcher| matcher.match_at(src, start, groups))
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for matcher in &self.0 {
+            bytes.extend(matcher.first_bytes()?);
+        }
+        Some(bytes)
+    }
+}
+
+pub struct Delimited {
+    open: String,
+    close: String,
+    whitespace: bool,
+}
+
+/// Matches `open`, then anything up to the next `close`, then `close`. The
+/// text in between is captured and can't be empty.
+pub fn delimited(open: impl Into<String>, close: impl Into<String>) -> Delimited {
+    Delimited {
+        open: open.into(),
+        close: close.into(),
+        whitespace: true,
+    }
+}
+
+impl Delimited {
+    /// Don't match if there's whitespace between the delimiters
+    pub fn no_whitespace(mut self) -> Delimited {
+        self.whitespace = false;
+        self
+    }
+}
+
+impl Matcher for Delimited {
+    fn match_at(&self, src: &str, start: usize, groups: &mut Groups) -> Option<usize> {
+        if !src[start..].starts_with(&self.open) {
+            return None;
+        }
+        let content_start = start + self.open.len();
+        let rest = &src[content_start..];
+        // Skip one char so the content is never empty
+        let first = rest.chars().next()?.len_utf8();
+        let content_end = content_start + first + rest[first..].find(&self.close)?;
+        let content = &src[content_start..content_end];
+        if !self.whitespace && content.contains(char::is_whitespace) {
+            return None;
+        }
+        groups.push(Some((content_start, content_end)));
+        Some(content_end + self.close.len())
+    }
+
+    fn first_bytes(&self) -> Option<Vec<u8>> {
+        self.open.bytes().next().map(|byte| vec![byte])
+    }
+}
+
+/// A rule that matches with a `Matcher` and builds its `ParseSpec` with a
+/// closure
+pub struct MatcherRule<M, F> {
+    matcher: M,
+    parse: F,
+    first_bytes: Option<Vec<u8>>,
+}
+
+impl<M: Matcher, F> MatcherRule<M, F> {
+    pub fn new(matcher: M, parse: F) -> MatcherRule<M, F> {
+        let first_bytes = matcher.first_bytes();
+        MatcherRule {
+            matcher,
+            parse,
+            first_bytes,
+        }
+    }
+}
+
+impl<T, S, M, F> Rule<T, S> for MatcherRule<M, F>
+where
+    T: Node<T>,
+    M: Matcher,
+    F: Fn(&Captures, &mut S) -> Result<ParseSpec<T>, RuleError> + Send + Sync,
+{
+    fn first_bytes(&self) -> Option<&[u8]> {
+        self.first_bytes.as_deref()
+    }
+
+    fn parse(&self, captures: &Captures, state: &mut S) -> Result<ParseSpec<T>, RuleError> {
+        (self.parse)(captures, state)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        self.matcher.captures(src)
+    }
+
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        Ok(self.matcher.captures_at(src, start, end))
+    }
+}
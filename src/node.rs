@@ -1,14 +1,28 @@
 use crate::parser::Styled;
-use std::{rc::Rc, sync::RwLock};
+use crate::Spanned;
 
-type NodeType = Rc<RwLock<MarkdownNode>>;
+type NodeType = Spanned<MarkdownNode>;
 
 pub trait Node<T> {
-    fn get_children(&self) -> Option<&[Rc<RwLock<T>>]>;
-    fn add_child(&mut self, child: Rc<RwLock<T>>);
+    fn get_children(&self) -> Option<&[Spanned<T>]>;
+    fn get_children_mut(&mut self) -> Option<&mut Vec<Spanned<T>>>;
+    fn add_child(&mut self, child: Spanned<T>);
+    /// Creates a leaf for source text that no rule matched
+    fn from_text(text: &str) -> T;
 }
 
-#[derive(Debug, Clone)]
+// Moves a node and all its children by `by` bytes
+pub(crate) fn shift_node<T: Node<T>>(node: &mut Spanned<T>, by: isize) {
+    node.span = node.span.shift(by);
+    node.content = node.content.shift(by);
+    if let Some(children) = node.get_children_mut() {
+        for child in children {
+            shift_node(child, by);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum MarkdownNode {
     Italic(Vec<NodeType>),
     Bold(Vec<NodeType>),
@@ -24,7 +38,8 @@ pub enum MarkdownNode {
     ChannelMention(u64),
     Emoji(String, u64),
     RoleMention(u64),
-    Timestamp(i64, Option<char>)
+    Timestamp(i64, Option<char>),
+    MaskedLink(Vec<NodeType>, String),
 }
 
 impl Node<MarkdownNode> for MarkdownNode {
@@ -45,6 +60,28 @@ impl Node<MarkdownNode> for MarkdownNode {
             MarkdownNode::Emoji(_, _) => None,
             MarkdownNode::RoleMention(_) => None,
             MarkdownNode::Timestamp(_, _) => None,
+            MarkdownNode::MaskedLink(children, _) => Some(children),
+        }
+    }
+
+    fn get_children_mut(&mut self) -> Option<&mut Vec<NodeType>> {
+        match self {
+            MarkdownNode::Italic(children) => Some(children),
+            MarkdownNode::Bold(children) => Some(children),
+            MarkdownNode::Underline(children) => Some(children),
+            MarkdownNode::Strikethrough(children) => Some(children),
+            MarkdownNode::Text(_) => None,
+            MarkdownNode::InlineCode(_) => None,
+            MarkdownNode::Code(_, _) => None,
+            MarkdownNode::Spoiler(children) => Some(children),
+            MarkdownNode::BlockQuote(children) => Some(children),
+            MarkdownNode::SingleBlockQuote(children) => Some(children),
+            MarkdownNode::UserMention(_) => None,
+            MarkdownNode::ChannelMention(_) => None,
+            MarkdownNode::Emoji(_, _) => None,
+            MarkdownNode::RoleMention(_) => None,
+            MarkdownNode::Timestamp(_, _) => None,
+            MarkdownNode::MaskedLink(children, _) => Some(children),
         }
     }
 
@@ -65,25 +102,30 @@ impl Node<MarkdownNode> for MarkdownNode {
             MarkdownNode::Emoji(_, _) => {}
             MarkdownNode::RoleMention(_) => {}
             MarkdownNode::Timestamp(_, _) => {}
+            MarkdownNode::MaskedLink(ref mut children, _) => children.push(child),
         }
     }
+
+    fn from_text(text: &str) -> MarkdownNode {
+        MarkdownNode::Text(text.to_owned())
+    }
 }
 
 impl Styled<MarkdownNode> {
     pub fn as_markdown(&self) -> String {
-        self.0
+        self.nodes
             .iter()
-            .map(|s| MarkdownNode::as_markdown(&*s.read().unwrap()))
+            .map(|s| MarkdownNode::as_markdown(s))
             .collect::<Vec<_>>()
             .join("")
     }
 }
 
 impl MarkdownNode {
-    fn collect(styles: &[Rc<RwLock<MarkdownNode>>]) -> String {
+    fn collect(styles: &[NodeType]) -> String {
         styles
             .iter()
-            .map(|s| MarkdownNode::as_markdown(&*s.read().unwrap()))
+            .map(|s| MarkdownNode::as_markdown(s))
             .collect::<Vec<_>>()
             .join("")
     }
@@ -120,12 +162,11 @@ impl MarkdownNode {
             RoleMention(id) => {
                 format!("<@&{}>", id)
             }
-            Timestamp(time, style) => {
-                match style {
-                    Some(style) => format!("<t:{}:{}>", time, style),
-                    None => format!("<t:{}>", time),
-                }
-            }
+            Timestamp(time, style) => match style {
+                Some(style) => format!("<t:{}:{}>", time, style),
+                None => format!("<t:{}>", time),
+            },
+            MaskedLink(styles, url) => format!("[{}]({})", MarkdownNode::collect(styles), url),
         }
     }
 }
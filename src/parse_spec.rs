@@ -1,13 +1,14 @@
 use crate::Node;
-use std::rc::Rc;
-use std::sync::RwLock;
 
 #[derive(Debug, Clone)]
 pub struct ParseSpec<T: Node<T>> {
-    pub root: Option<Rc<RwLock<T>>>,
+    pub root: Option<T>,
     pub is_terminal: bool,
     pub start_index: usize,
     pub end_index: usize,
+    /// The nested rule set to parse the content with (see
+    /// `RuleSet::add_nested`), `None` to keep the current one
+    pub rule_set: Option<String>,
 }
 
 impl<T: Node<T>> ParseSpec<T> {
@@ -17,42 +18,28 @@ impl<T: Node<T>> ParseSpec<T> {
         end_index: usize,
     ) -> ParseSpec<T> {
         ParseSpec {
-            root: root.map(|r| Rc::new(RwLock::new(r))),
+            root,
             is_terminal: false,
             start_index,
             end_index,
+            rule_set: None,
         }
     }
 
     pub fn create_terminal(root: Option<T>, start_index: usize, end_index: usize) -> ParseSpec<T> {
-        ParseSpec {
-            root: root.map(|r| Rc::new(RwLock::new(r))),
-            is_terminal: true,
-            start_index,
-            end_index,
-        }
-    }
-
-    pub fn create_wrapped_nonterminal(
-        root: Option<Rc<RwLock<T>>>,
-        start_index: usize,
-        end_index: usize,
-    ) -> ParseSpec<T> {
         ParseSpec {
             root,
-            is_terminal: false,
+            is_terminal: true,
             start_index,
             end_index,
+            rule_set: None,
         }
     }
 
-    pub fn create_wrapped_terminal(root: Option<Rc<RwLock<T>>>) -> ParseSpec<T> {
-        ParseSpec {
-            root,
-            is_terminal: true,
-            start_index: 0,
-            end_index: 0,
-        }
+    /// Parses the content with the nested rule set `name` instead
+    pub fn with_rule_set(mut self, name: impl Into<String>) -> ParseSpec<T> {
+        self.rule_set = Some(name.into());
+        self
     }
 
     pub fn apply_offset(&mut self, offset: usize) {
@@ -1,85 +1,278 @@
-use crate::{Node, ParseSpec, Rule};
-use std::rc::Rc;
-use std::sync::{RwLock, RwLockWriteGuard};
+use crate::rule_set::RuleId;
+use crate::{
+    Event, Events, Limit, Node, ParseError, ParseLimits, Rule, RuleSet, Span, Spanned, Trace,
+};
+use std::sync::{Arc, RwLock};
 
-pub struct Parser<'r, T: Node<T>> {
-    rules: &'r [&'r dyn Rule<T>],
+/// A configured set of rules, can be shared between threads (e.g. in an `Arc`)
+/// and reused for any number of parses
+pub struct Parser<T: Node<T>, S = ()> {
+    pub(crate) rules: RuleSet<T, S>,
+    pub(crate) limits: ParseLimits,
 }
 
-#[derive(Debug)]
-pub struct Styled<T: Node<T> + std::fmt::Debug>(pub Vec<Rc<RwLock<T>>>);
+#[derive(Debug, Clone, PartialEq)]
+pub struct Styled<T: Node<T> + std::fmt::Debug> {
+    pub nodes: Vec<Spanned<T>>,
+    /// Set if the parse was cut short by one of the parser's `ParseLimits`
+    pub limit_exceeded: Option<Limit>,
+}
 
-impl<'r, T: Node<T> + std::fmt::Debug> Parser<'r, T> {
-    pub fn with_rules(rules: &'r [&'r dyn Rule<T>]) -> Parser<'r, T> {
-        Parser { rules }
+impl<T: Node<T> + std::fmt::Debug> Styled<T> {
+    /// Wraps each top level node in a shared handle, for callers that want to
+    /// hand out references to nodes and mutate them in place
+    pub fn into_shared(self) -> Vec<Arc<RwLock<Spanned<T>>>> {
+        self.nodes
+            .into_iter()
+            .map(|node| Arc::new(RwLock::new(node)))
+            .collect()
     }
 
-    pub fn parse(&self, src: &str) -> Styled<T> {
-        let mut remaining_parses = Vec::new();
-        let mut top_level_nodes: Vec<Rc<RwLock<T>>> = Vec::new();
+    /// Returns every range of `src` that isn't covered by a node, e.g. text
+    /// consumed by a rule that didn't produce a node for it.
+    ///
+    /// Delimiters (the part of a node's span outside its content) count as
+    /// covered.
+    pub fn lost_ranges(&self, src: &str) -> Vec<Span> {
+        let mut lost = Vec::new();
+        Self::collect_lost(&self.nodes, Span::new(0, src.len()), &mut lost);
+        lost
+    }
 
-        if !src.is_empty() {
-            remaining_parses.push(ParseSpec::create_nonterminal(None, 0, src.len()));
+    fn collect_lost(nodes: &[Spanned<T>], within: Span, lost: &mut Vec<Span>) {
+        let mut covered = within.start;
+        for node in nodes {
+            if node.span.start > covered {
+                lost.push(Span::new(covered, node.span.start));
+            }
+            covered = covered.max(node.span.end);
+            if let Some(children) = node.get_children() {
+                Self::collect_lost(children, node.content, lost);
+            }
         }
+        if within.end > covered {
+            lost.push(Span::new(covered, within.end));
+        }
+    }
+}
 
-        let mut last_capture = None;
+impl<T: Node<T> + std::fmt::Debug + Clone, S: Clone> Parser<T, S> {
+    /// Creates a parser from a plain list of rules, named after `Rule::name`
+    pub fn with_rules(rules: Vec<Box<dyn Rule<T, S>>>) -> Parser<T, S> {
+        Parser::with_rule_set(RuleSet::from(rules))
+    }
 
-        while !remaining_parses.is_empty() {
-            let mut builder = remaining_parses
-                .pop()
-                .expect("remaining parses must not be empty");
+    pub fn with_rule_set(rules: RuleSet<T, S>) -> Parser<T, S> {
+        Parser {
+            rules,
+            limits: ParseLimits::default(),
+        }
+    }
 
-            if builder.start_index >= builder.end_index {
-                break;
-            }
+    pub fn rules(&self) -> &RuleSet<T, S> {
+        &self.rules
+    }
+
+    /// Gives access to the rules, e.g. to disable some of them
+    pub fn rules_mut(&mut self) -> &mut RuleSet<T, S> {
+        &mut self.rules
+    }
+
+    pub fn with_limits(mut self, limits: ParseLimits) -> Parser<T, S> {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &ParseLimits {
+        &self.limits
+    }
+
+    /// Parses `src` starting from the default state
+    pub fn parse(&self, src: &str) -> Styled<T>
+    where
+        S: Default,
+    {
+        self.parse_with_state(src, S::default())
+    }
+
+    /// Parses only `src[range]`, with spans pointing into `src`. E.g. a rule
+    /// can parse a node's content with a parser for another node type in
+    /// `Rule::finish`.
+    ///
+    /// Rules still see the text around the range, for lookbehind, `\b` or
+    /// whether a match starts a line, they just can't match past its end.
+    pub fn parse_fragment(&self, src: &str, range: Span) -> Styled<T>
+    where
+        S: Default,
+    {
+        let events = Events::within(self, src, range, S::default(), false);
+        self.build(src, events)
+            .unwrap_or_else(|_| unreachable!("only strict parses fail"))
+    }
+
+    /// Parses `src` with `state` passed to every rule.
+    ///
+    /// State is scoped to the tree: whatever a rule changes while opening a
+    /// nonterminal, or while parsing inside it, is rolled back once that
+    /// nonterminal is finished. Every call starts over from `state`.
+    ///
+    /// Rules that fail are skipped as if they hadn't matched.
+    pub fn parse_with_state(&self, src: &str, state: S) -> Styled<T> {
+        self.run(src, state, false)
+            .unwrap_or_else(|_| unreachable!("only strict parses fail"))
+    }
+
+    /// Parses every source in `sources` across several threads, the results
+    /// are in the same order as the sources
+    pub fn parse_many<I: AsRef<str> + Sync>(&self, sources: &[I]) -> Vec<Styled<T>>
+    where
+        S: Default,
+        T: Send,
+    {
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            sources
+                .par_iter()
+                .map(|src| self.parse(src.as_ref()))
+                .collect()
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        {
+            let threads = std::thread::available_parallelism().map_or(1, |threads| threads.get());
+            let chunk_size = sources.len().div_ceil(threads).max(1);
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = sources
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|src| self.parse(src.as_ref()))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().expect("a parse thread panicked"))
+                    .collect()
+            })
+        }
+    }
 
-            let inspection_source = &src[builder.start_index..builder.end_index];
-            let offset = builder.start_index;
-
-            for rule in self.rules {
-                let captures = rule.captures(inspection_source);
-                if let Some(matcher) = captures {
-                    if !rule.accept_match(last_capture) {
-                        continue;
-                    }
-                    let matcher_source_end = matcher.pos(0).unwrap().1 + offset;
-
-                    let mut new_builder = rule.parse(&matcher);
-                    let parent = &mut builder.root;
-
-                    if let Some(it) = new_builder.root.clone() {
-                        if let Some(ref mut parent) = parent {
-                            let mut parent: RwLockWriteGuard<T> = parent.write().unwrap();
-                            parent.add_child(it)
-                        } else {
-                            top_level_nodes.push(it)
-                        }
-                    }
-
-                    // In case the last match didn't consume the rest of the source for this subtree,
-                    // make sure the rest of the source is consumed.
-                    if matcher_source_end != builder.end_index {
-                        remaining_parses.push(ParseSpec::create_wrapped_nonterminal(
-                            builder.root,
-                            matcher_source_end,
-                            builder.end_index,
-                        ))
-                    }
-
-                    // We want to speak in terms of indices within the source string,
-                    // but the Rules only see the matchers in the context of the substring
-                    // being examined. Adding this offset addresses that issue.
-                    if !new_builder.is_terminal {
-                        new_builder.apply_offset(offset);
-                        remaining_parses.push(new_builder);
-                    }
-
-                    last_capture = matcher.at(0);
-                    break;
+    /// Like `parse`, but stops at the first rule that fails for any reason
+    /// other than `RuleError::Declined`
+    pub fn try_parse(&self, src: &str) -> Result<Styled<T>, ParseError>
+    where
+        S: Default,
+    {
+        self.try_parse_with_state(src, S::default())
+    }
+
+    pub fn try_parse_with_state(&self, src: &str, state: S) -> Result<Styled<T>, ParseError> {
+        self.run(src, state, true)
+    }
+
+    /// Parses `src` like `parse`, also recording which rules were tried where
+    /// and what came of them
+    pub fn parse_traced(&self, src: &str) -> (Styled<T>, Trace)
+    where
+        S: Default,
+    {
+        self.parse_traced_with_state(src, S::default())
+    }
+
+    pub fn parse_traced_with_state(&self, src: &str, state: S) -> (Styled<T>, Trace) {
+        let mut events = Events::new(self, src, state, false);
+        events.record_trace();
+        let mut builder = TreeBuilder::new(&self.rules, src);
+        while let Some((event, rule)) = events
+            .next_with_rule()
+            .unwrap_or_else(|_| unreachable!("only strict parses fail"))
+        {
+            builder.push(event, rule);
+        }
+        let trace = events.take_trace().expect("the trace is being recorded");
+        (builder.finish(events.limit_exceeded()), trace)
+    }
+
+    /// Parses `src` lazily, producing `Event`s instead of a tree
+    pub fn events<'s>(&self, src: &'s str) -> Events<'_, 's, T, S>
+    where
+        S: Default,
+    {
+        self.events_with_state(src, S::default())
+    }
+
+    pub fn events_with_state<'s>(&self, src: &'s str, state: S) -> Events<'_, 's, T, S> {
+        Events::new(self, src, state, false)
+    }
+
+    fn run(&self, src: &str, state: S, strict: bool) -> Result<Styled<T>, ParseError> {
+        self.build(src, Events::new(self, src, state, strict))
+    }
+
+    fn build(&self, src: &str, mut events: Events<'_, '_, T, S>) -> Result<Styled<T>, ParseError> {
+        let mut builder = TreeBuilder::new(&self.rules, src);
+        while let Some((event, rule)) = events.next_with_rule()? {
+            builder.push(event, rule);
+        }
+        Ok(builder.finish(events.limit_exceeded()))
+    }
+}
+
+// Assembles events back into a tree, running `Rule::finish` on every
+// finished nonterminal
+pub(crate) struct TreeBuilder<'a, T: Node<T>, S> {
+    rules: &'a RuleSet<T, S>,
+    src: &'a str,
+    pub(crate) top_level_nodes: Vec<Spanned<T>>,
+    open: Vec<(Spanned<T>, Option<RuleId>)>,
+}
+
+impl<'a, T: Node<T> + std::fmt::Debug, S> TreeBuilder<'a, T, S> {
+    pub(crate) fn new(rules: &'a RuleSet<T, S>, src: &'a str) -> TreeBuilder<'a, T, S> {
+        TreeBuilder {
+            rules,
+            src,
+            top_level_nodes: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+
+    // How many nodes are open
+    pub(crate) fn depth(&self) -> usize {
+        self.open.len()
+    }
+
+    pub(crate) fn push(&mut self, event: Event<T>, rule: Option<RuleId>) {
+        let node = match event {
+            Event::Start(node) => {
+                self.open.push((node, rule));
+                return;
+            }
+            Event::Text(text) => Spanned::new(T::from_text(&text), text.span, text.content),
+            Event::Leaf(node) => node,
+            Event::End(_) => {
+                let (mut node, rule) = self.open.pop().expect("end events must match start events");
+                if let Some(rule) = rule {
+                    self.rules.rule(rule).finish(&mut node, self.src);
                 }
+                node
             }
+        };
+        match self.open.last_mut() {
+            Some((parent, _)) => parent.add_child(node),
+            None => self.top_level_nodes.push(node),
         }
+    }
 
-        Styled(top_level_nodes)
+    pub(crate) fn finish(self, limit_exceeded: Option<Limit>) -> Styled<T> {
+        Styled {
+            nodes: self.top_level_nodes,
+            limit_exceeded,
+        }
     }
 }
@@ -0,0 +1,52 @@
+use super::{Captures, LimitExceeded, RegexEngine, RegexOptions};
+use fancy_regex::{Regex, RegexBuilder, RuntimeError};
+
+/// fancy-regex, which is pure Rust
+pub struct FancyRegex;
+
+impl RegexEngine for FancyRegex {
+    type Regex = Regex;
+    // Boxed, it's too big to pass around in a `Result`
+    type Error = Box<fancy_regex::Error>;
+
+    fn compile(pattern: &str, options: &RegexOptions) -> Result<Regex, Self::Error> {
+        let pattern = if options.case_insensitive {
+            format!("(?i){}", pattern)
+        } else {
+            pattern.to_owned()
+        };
+        let mut builder = RegexBuilder::new(&pattern);
+        if let Some(limit) = options.backtrack_limit {
+            builder.backtrack_limit(limit as usize);
+        }
+        builder.build().map_err(Box::new)
+    }
+
+    fn captures_at<'t>(
+        regex: &Regex,
+        text: &'t str,
+        start: usize,
+    ) -> Result<Option<Captures<'t>>, LimitExceeded> {
+        let captures = match regex.captures_from_pos(text, start) {
+            Ok(Some(captures)) => captures,
+            Ok(None) => return Ok(None),
+            // fancy-regex always has a backtrack limit, and a stack limit
+            Err(fancy_regex::Error::RuntimeError(
+                RuntimeError::BacktrackLimitExceeded | RuntimeError::StackOverflow,
+            )) => return Err(LimitExceeded),
+            Err(_) => return Ok(None),
+        };
+        let groups = (0..captures.len())
+            .map(|i| captures.get(i).map(|m| (m.start(), m.end())))
+            .collect();
+        Ok(Some(Captures::from_groups(text, groups)))
+    }
+
+    fn names(regex: &Regex) -> Vec<(String, usize)> {
+        regex
+            .capture_names()
+            .enumerate()
+            .filter_map(|(group, name)| Some((name?.to_owned(), group)))
+            .collect()
+    }
+}
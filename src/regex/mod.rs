@@ -1,66 +1,355 @@
-#[cfg(not(feature = "pcre"))]
-pub type Error = oniguruma::Error;
+//! Regexes, compiled by a `RegexEngine`.
+//!
+//! Each engine is behind its own cargo feature: `onig` (on by default) for
+//! Oniguruma, `pcre` for PCRE2 and `fancy` for fancy-regex, which is pure
+//! Rust. Several can be built at once and used side by side, e.g.
+//! `Regex::<Pcre2>::compile`. `Regex::new` uses `DefaultEngine`, which is
+//! Oniguruma if enabled, then PCRE2, then fancy-regex.
+
+use crate::RuleError;
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(feature = "fancy")]
+mod fancy;
+#[cfg(feature = "onig")]
+mod onig;
+#[cfg(feature = "pcre")]
+mod pcre;
+
+#[cfg(feature = "fancy")]
+pub use fancy::FancyRegex;
+#[cfg(feature = "onig")]
+pub use onig::Oniguruma;
 #[cfg(feature = "pcre")]
-pub type Error = pcre2::Error;
+pub use pcre::Pcre2;
+
+#[cfg(not(any(feature = "onig", feature = "pcre", feature = "fancy")))]
+compile_error!("one of the onig, pcre or fancy features has to be enabled");
+
+// Oniguruma whenever it's enabled, so enabling another engine somewhere in
+// the dependency graph doesn't change how the built in rules parse
+#[cfg(feature = "onig")]
+pub type DefaultEngine = Oniguruma;
+#[cfg(all(feature = "pcre", not(feature = "onig")))]
+pub type DefaultEngine = Pcre2;
+#[cfg(not(any(feature = "onig", feature = "pcre")))]
+pub type DefaultEngine = FancyRegex;
+
+/// The error for patterns `DefaultEngine` can't compile
+pub type Error = <DefaultEngine as RegexEngine>::Error;
+
+/// A regex library. Rules can be generic over it, or store a `Regex` for
+/// an engine picked when the rules are built.
+pub trait RegexEngine: Send + Sync + 'static {
+    /// A compiled pattern
+    type Regex: Send + Sync;
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Compiles `pattern`, ignoring options the engine doesn't support
+    fn compile(pattern: &str, options: &RegexOptions) -> Result<Self::Regex, Self::Error>;
+
+    /// Finds the first match in `text` that starts at `start` or later.
+    /// Lookbehind and `\b` see the text before `start`, and `\G` matches at
+    /// `start`.
+    fn captures_at<'t>(
+        regex: &Self::Regex,
+        text: &'t str,
+        start: usize,
+    ) -> Result<Option<Captures<'t>>, LimitExceeded>;
+
+    /// The named groups and their numbers. A name can be used for more than
+    /// one group.
+    fn names(_regex: &Self::Regex) -> Vec<(String, usize)> {
+        Vec::new()
+    }
+}
+
+/// Settings for compiling a `Regex`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RegexOptions {
+    pub case_insensitive: bool,
+    /// Compile to machine code where the engine can (PCRE2 only)
+    pub jit: bool,
+    /// How many times a match may backtrack: Oniguruma's retry limit,
+    /// PCRE2's match limit and fancy-regex's backtrack limit
+    pub backtrack_limit: Option<u32>,
+    /// How deeply a match may nest its backtracking: Oniguruma's match stack
+    /// limit and PCRE2's depth limit. fancy-regex ignores it.
+    pub depth_limit: Option<u32>,
+}
+
+impl RegexOptions {
+    pub fn new() -> RegexOptions {
+        RegexOptions::default()
+    }
+
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> RegexOptions {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    pub fn jit(mut self, jit: bool) -> RegexOptions {
+        self.jit = jit;
+        self
+    }
 
-pub struct Regex {
-    #[cfg(not(feature = "pcre"))]
-    inner: oniguruma::Regex,
+    pub fn backtrack_limit(mut self, backtrack_limit: u32) -> RegexOptions {
+        self.backtrack_limit = Some(backtrack_limit);
+        self
+    }
+
+    pub fn depth_limit(mut self, depth_limit: u32) -> RegexOptions {
+        self.depth_limit = Some(depth_limit);
+        self
+    }
+}
+
+/// A match gave up after hitting one of its `RegexOptions` limits, it's
+/// treated as not matching
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitExceeded;
 
-    #[cfg(feature = "pcre")]
-    inner: pcre2::bytes::Regex,
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "regex match limit exceeded")
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+// Group names and numbers, shared by a regex and its captures
+type Names = Option<Arc<[(String, usize)]>>;
+
+pub struct Regex<E: RegexEngine = DefaultEngine> {
+    inner: E::Regex,
+    names: Names,
 }
 
 impl Regex {
     pub fn new(pattern: &str) -> Result<Regex, Error> {
-        #[cfg(not(feature = "pcre"))]
-        let regex = oniguruma::Regex::with_options(
-            pattern,
-            oniguruma::RegexOptions::REGEX_OPTION_NONE,
-            oniguruma::Syntax::perl(),
-        );
-        #[cfg(feature = "pcre")]
-        let regex = pcre2::bytes::RegexBuilder::new().utf(true).build(pattern);
+        Regex::compile(pattern)
+    }
 
-        regex.map(|regex| Regex { inner: regex })
+    pub fn with_options(pattern: &str, options: RegexOptions) -> Result<Regex, Error> {
+        Regex::compile_with_options(pattern, options)
+    }
+}
+
+impl<E: RegexEngine> Regex<E> {
+    /// Compiles `pattern` with the engine `E`
+    pub fn compile(pattern: &str) -> Result<Regex<E>, E::Error> {
+        Regex::compile_with_options(pattern, RegexOptions::default())
+    }
+
+    pub fn compile_with_options(
+        pattern: &str,
+        options: RegexOptions,
+    ) -> Result<Regex<E>, E::Error> {
+        let inner = E::compile(pattern, &options)?;
+        let names = E::names(&inner);
+        Ok(Regex {
+            names: if names.is_empty() {
+                None
+            } else {
+                Some(names.into())
+            },
+            inner,
+        })
     }
 
+    /// Finds the first match in `text`, `None` if there is none or a limit
+    /// was hit
     pub fn captures<'t>(&self, text: &'t str) -> Option<Captures<'t>> {
-        #[cfg(not(feature = "pcre"))]
-        let captures = self.inner.captures(text);
-        #[cfg(feature = "pcre")]
-        let captures = self.inner.captures(text.as_bytes()).ok().and_then(|c| c);
+        let mut captures = E::captures_at(&self.inner, text, 0).ok()??;
+        captures.names = self.names.clone();
+        Some(captures)
+    }
 
-        captures.map(|c| Captures { inner: c })
+    /// Matches at `start` without looking past `end`, positions index
+    /// `text`. Unlike matching `text[start..end]`, lookbehind and `\b` see
+    /// what comes before `start`. Start the pattern with `\G` rather than
+    /// `^` so it's anchored there, or the engine searches on past `start`.
+    pub fn captures_at<'t>(
+        &self,
+        text: &'t str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'t>>, LimitExceeded> {
+        let mut captures = match E::captures_at(&self.inner, &text[..end], start)? {
+            Some(captures) if captures.pos(0).map(|(found, _)| found) == Some(start) => captures,
+            _ => return Ok(None),
+        };
+        captures.names = self.names.clone();
+        Ok(Some(captures))
     }
 }
 
 pub struct Captures<'a> {
-    #[cfg(not(feature = "pcre"))]
-    inner: oniguruma::Captures<'a>,
+    text: &'a str,
+    // Where `text` starts in the source the groups index
+    offset: usize,
+    groups: Vec<Option<(usize, usize)>>,
+    names: Names,
+}
 
-    #[cfg(feature = "pcre")]
-    inner: pcre2::bytes::Captures<'a>,
+/// A capture group that took part in a match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Group<'t> {
+    pub start: usize,
+    pub end: usize,
+    pub text: &'t str,
 }
 
 impl<'t> Captures<'t> {
-    pub fn pos(&self, pos: usize) -> Option<(usize, usize)> {
-        #[cfg(not(feature = "pcre"))]
-        return self.inner.pos(pos);
+    /// Captures found in `text`, `groups[0]` is the whole match. The ranges
+    /// must lie on char boundaries of `text`.
+    pub fn from_groups(text: &'t str, groups: Vec<Option<(usize, usize)>>) -> Captures<'t> {
+        Captures {
+            text,
+            offset: 0,
+            groups,
+            names: None,
+        }
+    }
+
+    // Moves captures found in `src[start..]` to index `src` instead
+    pub(crate) fn into_source(mut self, src: &'t str, start: usize) -> Captures<'t> {
+        self.text = src;
+        self.offset = 0;
+        for (group_start, group_end) in self.groups.iter_mut().flatten() {
+            *group_start += start;
+            *group_end += start;
+        }
+        self
+    }
+
+    pub fn try_pos(&self, pos: usize) -> Result<(usize, usize), RuleError> {
+        self.pos(pos).ok_or(RuleError::MissingGroup(pos))
+    }
 
-        #[cfg(feature = "pcre")]
-        return self.inner.get(pos).map(|m| (m.start(), m.end()));
+    pub fn try_at(&self, pos: usize) -> Result<&'t str, RuleError> {
+        self.at(pos).ok_or(RuleError::MissingGroup(pos))
+    }
+
+    pub fn pos(&self, pos: usize) -> Option<(usize, usize)> {
+        self.groups.get(pos).copied().flatten()
     }
 
     pub fn at(&self, pos: usize) -> Option<&'t str> {
-        #[cfg(not(feature = "pcre"))]
-        return self.inner.at(pos);
-
-        #[cfg(feature = "pcre")]
-        return self
-            .inner
-            .get(pos)
-            .map(|m| unsafe { std::str::from_utf8_unchecked(m.as_bytes()) });
-        // utf8 must be valid for the regex object to be constructed
+        self.pos(pos).map(|(start, end)| self.slice(start, end))
+    }
+
+    pub fn try_name_pos(&self, name: &str) -> Result<(usize, usize), RuleError> {
+        self.name_pos(name)
+            .ok_or_else(|| RuleError::MissingNamedGroup(name.to_owned()))
+    }
+
+    pub fn try_name(&self, name: &str) -> Result<&'t str, RuleError> {
+        self.name(name)
+            .ok_or_else(|| RuleError::MissingNamedGroup(name.to_owned()))
+    }
+
+    /// The position of the group called `name`, or of the first one that
+    /// matched if several share the name
+    pub fn name_pos(&self, name: &str) -> Option<(usize, usize)> {
+        self.names()
+            .filter(|&(group_name, _)| group_name == name)
+            .find_map(|(_, group)| self.pos(group))
+    }
+
+    pub fn name(&self, name: &str) -> Option<&'t str> {
+        self.name_pos(name)
+            .map(|(start, end)| self.slice(start, end))
+    }
+
+    /// The number of groups, including group 0 and groups that didn't match
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Every group in order, `None` for the ones that didn't match
+    pub fn iter(&self) -> impl Iterator<Item = Option<Group<'t>>> + '_ {
+        self.groups.iter().map(move |group| {
+            group.map(|(start, end)| Group {
+                start,
+                end,
+                text: self.slice(start, end),
+            })
+        })
+    }
+
+    /// The named groups and their numbers
+    pub fn names(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.names
+            .iter()
+            .flat_map(|names| names.iter())
+            .map(|(name, group)| (name.as_str(), *group))
+    }
+
+    /// Copies the captures out of the source. Only the text from the earliest
+    /// group start to the latest group end is kept, positions still index the
+    /// source.
+    pub fn into_owned(self) -> OwnedCaptures {
+        let groups = || self.groups.iter().flatten();
+        let start = groups().map(|&(start, _)| start).min();
+        let end = groups().map(|&(_, end)| end).max();
+        let (start, end) = start.zip(end).unwrap_or((self.offset, self.offset));
+        OwnedCaptures {
+            text: self.slice(start, end).to_owned(),
+            offset: start,
+            groups: self.groups,
+            names: self.names,
+        }
+    }
+
+    fn slice(&self, start: usize, end: usize) -> &'t str {
+        &self.text[start - self.offset..end - self.offset]
+    }
+}
+
+/// `Captures` that own their text, see `Captures::into_owned`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedCaptures {
+    text: String,
+    offset: usize,
+    groups: Vec<Option<(usize, usize)>>,
+    names: Names,
+}
+
+impl OwnedCaptures {
+    pub fn pos(&self, pos: usize) -> Option<(usize, usize)> {
+        self.groups.get(pos).copied().flatten()
+    }
+
+    pub fn at(&self, pos: usize) -> Option<&str> {
+        self.pos(pos)
+            .map(|(start, end)| &self.text[start - self.offset..end - self.offset])
+    }
+
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.as_captures().name(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Borrows the captures back, for the rest of the `Captures` API
+    pub fn as_captures(&self) -> Captures<'_> {
+        Captures {
+            text: &self.text,
+            offset: self.offset,
+            groups: self.groups.clone(),
+            names: self.names.clone(),
+        }
     }
 }
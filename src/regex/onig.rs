@@ -0,0 +1,93 @@
+use super::{Captures, LimitExceeded, RegexEngine, RegexOptions};
+use oniguruma::{MatchParam, Region, SearchOptions};
+
+/// Oniguruma with Perl syntax, plus named groups
+pub struct Oniguruma;
+
+pub struct OnigRegex {
+    regex: oniguruma::Regex,
+    retry_limit: Option<u32>,
+    stack_limit: Option<u32>,
+}
+
+// The error codes for running into a match parameter's limits
+const LIMIT_ERRORS: &[i32] = &[
+    -15, // ONIGERR_MATCH_STACK_LIMIT_OVER
+    -17, // ONIGERR_RETRY_LIMIT_IN_MATCH_OVER
+    -18, // ONIGERR_RETRY_LIMIT_IN_SEARCH_OVER
+];
+
+impl RegexEngine for Oniguruma {
+    type Regex = OnigRegex;
+    type Error = oniguruma::Error;
+
+    fn compile(pattern: &str, options: &RegexOptions) -> Result<OnigRegex, oniguruma::Error> {
+        // Oniguruma stops numbering unnamed groups once a pattern has named
+        // ones, unlike the other engines
+        let mut flags = oniguruma::RegexOptions::REGEX_OPTION_CAPTURE_GROUP;
+        if options.case_insensitive {
+            flags |= oniguruma::RegexOptions::REGEX_OPTION_IGNORECASE;
+        }
+        let regex = oniguruma::Regex::with_options(pattern, flags, oniguruma::Syntax::perl_ng())?;
+        Ok(OnigRegex {
+            regex,
+            retry_limit: options.backtrack_limit,
+            stack_limit: options.depth_limit,
+        })
+    }
+
+    fn captures_at<'t>(
+        regex: &OnigRegex,
+        text: &'t str,
+        start: usize,
+    ) -> Result<Option<Captures<'t>>, LimitExceeded> {
+        let mut region = Region::new();
+        let found = if regex.retry_limit.is_none() && regex.stack_limit.is_none() {
+            regex.regex.search_with_options(
+                text,
+                start,
+                text.len(),
+                SearchOptions::SEARCH_OPTION_NONE,
+                Some(&mut region),
+            )
+        } else {
+            let mut param = MatchParam::default();
+            if let Some(limit) = regex.retry_limit {
+                param.set_retry_limit_in_match(limit);
+            }
+            if let Some(limit) = regex.stack_limit {
+                param.set_match_stack_limit(limit);
+            }
+            match regex.regex.search_with_param(
+                text,
+                start,
+                text.len(),
+                SearchOptions::SEARCH_OPTION_NONE,
+                Some(&mut region),
+                param,
+            ) {
+                Ok(found) => found,
+                Err(error) if LIMIT_ERRORS.contains(&error.code()) => return Err(LimitExceeded),
+                Err(_) => None,
+            }
+        };
+        if found.is_none() {
+            return Ok(None);
+        }
+        let groups = (0..region.len()).map(|i| region.pos(i)).collect();
+        Ok(Some(Captures::from_groups(text, groups)))
+    }
+
+    fn names(regex: &OnigRegex) -> Vec<(String, usize)> {
+        let mut names = Vec::new();
+        regex.regex.foreach_name(|name, groups| {
+            names.extend(
+                groups
+                    .iter()
+                    .map(|&group| (name.to_owned(), group as usize)),
+            );
+            true
+        });
+        names
+    }
+}
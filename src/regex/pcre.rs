@@ -0,0 +1,62 @@
+use super::{Captures, LimitExceeded, RegexEngine, RegexOptions};
+use pcre2::bytes::{Regex, RegexBuilder};
+
+/// PCRE2, in UTF mode with Unicode `\w`, `\d`, `\s` and `\b` like the other
+/// engines
+pub struct Pcre2;
+
+// The error codes for running into a limit while matching
+const LIMIT_ERRORS: &[i32] = &[
+    -46, // PCRE2_ERROR_JIT_STACKLIMIT
+    -47, // PCRE2_ERROR_MATCHLIMIT
+    -53, // PCRE2_ERROR_DEPTHLIMIT
+    -63, // PCRE2_ERROR_HEAPLIMIT
+];
+
+impl RegexEngine for Pcre2 {
+    type Regex = Regex;
+    type Error = pcre2::Error;
+
+    fn compile(pattern: &str, options: &RegexOptions) -> Result<Regex, pcre2::Error> {
+        // The pcre2 crate doesn't expose match limits, but a pattern can
+        // lower its own
+        let mut limited = String::new();
+        if let Some(limit) = options.backtrack_limit {
+            limited.push_str(&format!("(*LIMIT_MATCH={})", limit));
+        }
+        if let Some(limit) = options.depth_limit {
+            limited.push_str(&format!("(*LIMIT_DEPTH={})", limit));
+        }
+        limited.push_str(pattern);
+        RegexBuilder::new()
+            .utf(true)
+            .ucp(true)
+            .caseless(options.case_insensitive)
+            .jit_if_available(options.jit)
+            .build(&limited)
+    }
+
+    fn captures_at<'t>(
+        regex: &Regex,
+        text: &'t str,
+        start: usize,
+    ) -> Result<Option<Captures<'t>>, LimitExceeded> {
+        let mut locations = regex.capture_locations();
+        match regex.captures_read_at(&mut locations, text.as_bytes(), start) {
+            Ok(Some(_)) => {}
+            Err(error) if LIMIT_ERRORS.contains(&error.code()) => return Err(LimitExceeded),
+            _ => return Ok(None),
+        }
+        let groups = (0..locations.len()).map(|i| locations.get(i)).collect();
+        Ok(Some(Captures::from_groups(text, groups)))
+    }
+
+    fn names(regex: &Regex) -> Vec<(String, usize)> {
+        regex
+            .capture_names()
+            .iter()
+            .enumerate()
+            .filter_map(|(group, name)| Some((name.clone()?, group)))
+            .collect()
+    }
+}
@@ -1,10 +1,81 @@
-use crate::regex::Captures;
-use crate::Node;
+use crate::regex::{Captures, LimitExceeded};
+use crate::{Node, RuleError, Spanned};
 
-pub trait Rule<T: Node<T>> {
-    fn accept_match(&self, _last_capture: Option<&str>) -> bool {
+/// A single syntax rule, `S` is the per-parse state the parser hands to every
+/// rule (see `Parser::parse_with_state`)
+pub trait Rule<T: Node<T>, S = ()>: Send + Sync {
+    /// The name shown for the rule, e.g. in a `Trace`. Defaults to the name of
+    /// the type.
+    fn name(&self) -> &str {
+        let name = std::any::type_name::<Self>();
+        let end = name.find('<').unwrap_or(name.len());
+        let start = name[..end].rfind("::").map_or(0, |separator| separator + 2);
+        &name[start..]
+    }
+    fn accept_match(&self, _context: &MatchContext<T>, _state: &S) -> bool {
+        true
+    }
+    /// Whether this rule may match again inside a node it created
+    fn allows_nesting(&self) -> bool {
         true
     }
-    fn parse(&self, captures: &Captures) -> crate::ParseSpec<T>;
+    /// The bytes a match can start with, `None` if it can start with anything.
+    /// The parser doesn't try the rule anywhere else.
+    fn first_bytes(&self) -> Option<&[u8]> {
+        None
+    }
+    /// Turns a match into a `ParseSpec`, returning an error to give the match
+    /// up and let the following rules try instead. The captures come from
+    /// `captures_at`, so the spec's range indexes the whole source.
+    fn parse(&self, captures: &Captures, state: &mut S) -> Result<crate::ParseSpec<T>, RuleError>;
     fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>>;
+    /// Matches at `start` in `src` without looking past `end`, with positions
+    /// indexing `src`. The default runs `captures` on `src[start..end]`; rules
+    /// that need to see the text before `start`, e.g. for lookbehind or `\b`,
+    /// or that want regex limits reported, override it (see
+    /// `Regex::captures_at`).
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        Ok(self
+            .captures(&src[start..end])
+            .map(|captures| captures.into_source(src, start)))
+    }
+    /// How far into `src` matching at `start` could have looked, at most
+    /// `end`, `match_end` being where the match ends or `None` if there was
+    /// none. A lookahead looks past the match, a search that failed could have
+    /// gone up to `end`. `Parser::reparse` only matches the rule again when an
+    /// edit starts before that. Defaults to `end`.
+    fn reach(&self, _src: &str, _start: usize, _match_end: Option<usize>, end: usize) -> usize {
+        end
+    }
+    /// Called with every nonterminal this rule created once all its children
+    /// have been added, `src` being the whole source. Only runs when a tree is
+    /// built, `Parser::events` streams nodes before they're finished.
+    fn finish(&self, _node: &mut Spanned<T>, _src: &str) {}
+}
+
+/// Where a match was found, for `Rule::accept_match`
+pub struct MatchContext<'a, T> {
+    /// The text of the previous match, wherever it was
+    pub last_capture: Option<&'a str>,
+    pub at_source_start: bool,
+    /// Whether the match starts the source or follows a newline
+    pub at_line_start: bool,
+    /// The nodes the match would be nested in, outermost first. They don't
+    /// have their children yet.
+    pub ancestors: &'a [Spanned<T>],
+    /// The node right before the match in the same parent, without children.
+    /// Text no rule matched counts, as the node `Node::from_text` makes of it.
+    pub previous_sibling: Option<&'a Spanned<T>>,
+}
+
+impl<'a, T> MatchContext<'a, T> {
+    /// The node the match would be added to, `None` at the top level
+    pub fn parent(&self) -> Option<&'a Spanned<T>> {
+        self.ancestors.last()
+    }
 }
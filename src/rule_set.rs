@@ -0,0 +1,327 @@
+use crate::{Node, Rule, RuleSetError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+/// An ordered list of named rules, earlier rules are tried first.
+///
+/// Rules can be switched off and on again without losing their place.
+/// Cloning a `RuleSet` shares the rules themselves, so e.g. a per channel copy
+/// with some rules disabled is cheap.
+///
+/// A rule set can also hold named nested rule sets, which a `ParseSpec` can
+/// pick to parse its content with (see `ParseSpec::with_rule_set`). Enabling,
+/// disabling or removing a rule by name also reaches the nested sets, adding
+/// rules and looking them up doesn't.
+pub struct RuleSet<T: Node<T>, S = ()> {
+    entries: Vec<Entry<T, S>>,
+    nested: Vec<(String, RuleSet<T, S>)>,
+    // The enabled rules that can match at each leading byte, in order
+    pub(crate) dispatch: Vec<Vec<usize>>,
+    // Unique to this list of rules, so old parses can tell they're out of date
+    pub(crate) version: u64,
+}
+
+// A rule in a rule set or one of its nested sets, which are numbered from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RuleId {
+    pub(crate) set: usize,
+    pub(crate) index: usize,
+}
+
+struct Entry<T: Node<T>, S> {
+    name: String,
+    rule: Arc<dyn Rule<T, S>>,
+    enabled: bool,
+}
+
+impl<T: Node<T>, S> RuleSet<T, S> {
+    pub fn new() -> RuleSet<T, S> {
+        RuleSet {
+            entries: Vec::new(),
+            nested: Vec::new(),
+            dispatch: vec![Vec::new(); 256],
+            version: 0,
+        }
+    }
+
+    /// Adds a rule after all the others
+    pub fn push(
+        &mut self,
+        name: impl Into<String>,
+        rule: impl Rule<T, S> + 'static,
+    ) -> Result<(), RuleSetError> {
+        self.insert_at(self.entries.len(), name.into(), Arc::new(rule))
+    }
+
+    /// Like `push`, for chaining while setting a rule set up. Panics if the
+    /// name is already taken.
+    pub fn with(mut self, name: impl Into<String>, rule: impl Rule<T, S> + 'static) -> Self {
+        if let Err(error) = self.push(name, rule) {
+            panic!("{}", error);
+        }
+        self
+    }
+
+    /// Adds a rule right before the rule called `before`, so it's tried first
+    pub fn insert_before(
+        &mut self,
+        before: &str,
+        name: impl Into<String>,
+        rule: impl Rule<T, S> + 'static,
+    ) -> Result<(), RuleSetError> {
+        let index = self.find(before)?;
+        self.insert_at(index, name.into(), Arc::new(rule))
+    }
+
+    /// Adds a rule right after the rule called `after`
+    pub fn insert_after(
+        &mut self,
+        after: &str,
+        name: impl Into<String>,
+        rule: impl Rule<T, S> + 'static,
+    ) -> Result<(), RuleSetError> {
+        let index = self.find(after)?;
+        self.insert_at(index + 1, name.into(), Arc::new(rule))
+    }
+
+    /// Removes the rule called `name` here and from every nested set, returns
+    /// this set's rule if it has one
+    pub fn remove(&mut self, name: &str) -> Result<Arc<dyn Rule<T, S>>, RuleSetError> {
+        let mut removed = self
+            .position(name)
+            .map(|index| self.entries.remove(index).rule);
+        for (_, rules) in &mut self.nested {
+            if let Ok(rule) = rules.remove(name) {
+                removed.get_or_insert(rule);
+            }
+        }
+        let removed = removed.ok_or_else(|| RuleSetError::Unknown(name.to_owned()))?;
+        self.changed();
+        Ok(removed)
+    }
+
+    pub fn enable(&mut self, name: &str) -> Result<(), RuleSetError> {
+        self.set_enabled(name, true)
+    }
+
+    /// Stops the parser from trying the rule until it's enabled again
+    pub fn disable(&mut self, name: &str) -> Result<(), RuleSetError> {
+        self.set_enabled(name, false)
+    }
+
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), RuleSetError> {
+        let mut found = false;
+        if let Some(index) = self.position(name) {
+            self.entries[index].enabled = enabled;
+            found = true;
+        }
+        for (_, rules) in &mut self.nested {
+            found |= rules.set_enabled(name, enabled).is_ok();
+        }
+        if !found {
+            return Err(RuleSetError::Unknown(name.to_owned()));
+        }
+        self.changed();
+        Ok(())
+    }
+
+    /// Whether this set's rule called `name` is enabled, nested sets aren't
+    /// checked
+    pub fn is_enabled(&self, name: &str) -> Option<bool> {
+        self.position(name).map(|index| self.entries[index].enabled)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Rule<T, S>> {
+        self.position(name).map(|index| &*self.entries[index].rule)
+    }
+
+    /// The names of all rules, in the order they're tried
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|entry| entry.name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds a rule set that content can be parsed with instead. Names are
+    /// always looked up in the parser's own rule set, even from nested ones.
+    pub fn add_nested(
+        &mut self,
+        name: impl Into<String>,
+        rules: RuleSet<T, S>,
+    ) -> Result<(), RuleSetError> {
+        let name = name.into();
+        if self.nested(&name).is_some() {
+            return Err(RuleSetError::Duplicate(name));
+        }
+        self.nested.push((name, rules));
+        self.version = next_version();
+        Ok(())
+    }
+
+    /// Like `add_nested`, for chaining. Panics if the name is already taken.
+    pub fn with_nested(mut self, name: impl Into<String>, rules: RuleSet<T, S>) -> Self {
+        if let Err(error) = self.add_nested(name, rules) {
+            panic!("{}", error);
+        }
+        self
+    }
+
+    pub fn nested(&self, name: &str) -> Option<&RuleSet<T, S>> {
+        self.nested_id(name).map(|set| self.set(set))
+    }
+
+    pub fn nested_mut(&mut self, name: &str) -> Option<&mut RuleSet<T, S>> {
+        // The caller may change it, which changes how this set parses too
+        self.version = next_version();
+        self.nested
+            .iter_mut()
+            .find(|(nested, _)| nested == name)
+            .map(|(_, rules)| rules)
+    }
+
+    pub fn remove_nested(&mut self, name: &str) -> Result<RuleSet<T, S>, RuleSetError> {
+        let set = self
+            .nested_id(name)
+            .ok_or_else(|| RuleSetError::Unknown(name.to_owned()))?;
+        self.version = next_version();
+        Ok(self.nested.remove(set - 1).1)
+    }
+
+    pub(crate) fn nested_id(&self, name: &str) -> Option<usize> {
+        self.nested
+            .iter()
+            .position(|(nested, _)| nested == name)
+            .map(|index| index + 1)
+    }
+
+    pub(crate) fn set(&self, set: usize) -> &RuleSet<T, S> {
+        match set {
+            0 => self,
+            _ => &self.nested[set - 1].1,
+        }
+    }
+
+    pub(crate) fn rule(&self, id: RuleId) -> &dyn Rule<T, S> {
+        &*self.set(id.set).entries[id.index].rule
+    }
+
+    pub(crate) fn name(&self, id: RuleId) -> &str {
+        &self.set(id.set).entries[id.index].name
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.name == name)
+    }
+
+    fn find(&self, name: &str) -> Result<usize, RuleSetError> {
+        self.position(name)
+            .ok_or_else(|| RuleSetError::Unknown(name.to_owned()))
+    }
+
+    fn insert_at(
+        &mut self,
+        index: usize,
+        name: String,
+        rule: Arc<dyn Rule<T, S>>,
+    ) -> Result<(), RuleSetError> {
+        if self.contains(&name) {
+            return Err(RuleSetError::Duplicate(name));
+        }
+        self.entries.insert(
+            index,
+            Entry {
+                name,
+                rule,
+                enabled: true,
+            },
+        );
+        self.changed();
+        Ok(())
+    }
+
+    fn changed(&mut self) {
+        self.version = next_version();
+        for rules in &mut self.dispatch {
+            rules.clear();
+        }
+        for (index, entry) in self.entries.iter().enumerate() {
+            if !entry.enabled {
+                continue;
+            }
+            match entry.rule.first_bytes() {
+                Some(bytes) => {
+                    for &byte in bytes {
+                        let rules = &mut self.dispatch[byte as usize];
+                        if rules.last() != Some(&index) {
+                            rules.push(index);
+                        }
+                    }
+                }
+                None => self.dispatch.iter_mut().for_each(|rules| rules.push(index)),
+            }
+        }
+    }
+}
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+impl<T: Node<T>, S> Default for RuleSet<T, S> {
+    fn default() -> Self {
+        RuleSet::new()
+    }
+}
+
+impl<T: Node<T>, S> Clone for RuleSet<T, S> {
+    fn clone(&self) -> Self {
+        RuleSet {
+            entries: self
+                .entries
+                .iter()
+                .map(|entry| Entry {
+                    name: entry.name.clone(),
+                    rule: Arc::clone(&entry.rule),
+                    enabled: entry.enabled,
+                })
+                .collect(),
+            nested: self.nested.clone(),
+            dispatch: self.dispatch.clone(),
+            version: self.version,
+        }
+    }
+}
+
+impl<T: Node<T>, S> From<Vec<Box<dyn Rule<T, S>>>> for RuleSet<T, S> {
+    /// Names each rule after `Rule::name`, adding a number to repeated names
+    fn from(rules: Vec<Box<dyn Rule<T, S>>>) -> Self {
+        let mut set = RuleSet::new();
+        for rule in rules {
+            let mut name = rule.name().to_owned();
+            let mut count = 1;
+            while set.contains(&name) {
+                count += 1;
+                name = format!("{}{}", rule.name(), count);
+            }
+            set.entries.push(Entry {
+                name,
+                rule: Arc::from(rule),
+                enabled: true,
+            });
+        }
+        set.changed();
+        set
+    }
+}
@@ -0,0 +1,83 @@
+use std::ops::{Deref, DerefMut, Range};
+
+/// A byte range within the parsed source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start >= self.end
+    }
+
+    pub fn contains(&self, pos: usize) -> bool {
+        self.start <= pos && pos < self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// Moves the span by `by` bytes, e.g. after an edit earlier in the source
+    pub fn shift(self, by: isize) -> Span {
+        Span::new(
+            (self.start as isize + by) as usize,
+            (self.end as isize + by) as usize,
+        )
+    }
+}
+
+impl From<Range<usize>> for Span {
+    fn from(range: Range<usize>) -> Span {
+        Span::new(range.start, range.end)
+    }
+}
+
+/// A node along with where it was found in the source.
+///
+/// `span` covers the whole match, delimiters included, while `content` only
+/// covers the part handed to the node's children (or the terminal's text).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+    pub content: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span, content: Span) -> Spanned<T> {
+        Spanned {
+            node,
+            span,
+            content,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.node
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+impl<T> DerefMut for Spanned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.node
+    }
+}
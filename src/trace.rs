@@ -0,0 +1,116 @@
+use crate::{Limit, RuleError, Span};
+use std::fmt;
+
+/// A record of every rule the parser tried and what came of it, see
+/// `Parser::parse_traced`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub source: String,
+    pub steps: Vec<TraceStep>,
+}
+
+/// The rules tried against one range of the source, in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    /// The part of the source left to parse in the current node, matches
+    /// must start at its beginning
+    pub range: Span,
+    pub attempts: Vec<RuleAttempt>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleAttempt {
+    /// The rule's position in its rule set
+    pub rule: usize,
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+/// What happened when a rule was tried
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// `captures` found nothing
+    NoMatch,
+    /// The rule's regex gave up on a limit from its `RegexOptions`, which
+    /// counts as no match
+    MatchLimitExceeded,
+    /// `accept_match` turned the match down
+    Rejected(Span),
+    /// `parse` failed, or returned a range outside the match
+    Failed(Span, RuleError),
+    /// Using the match would have gone over one of the parser's limits
+    LimitExceeded(Limit),
+    /// The match was used, pushing a spec for `content`
+    Pushed {
+        span: Span,
+        content: Span,
+        is_terminal: bool,
+        has_node: bool,
+    },
+}
+
+impl TraceStep {
+    /// The attempt whose match was used, if any
+    pub fn pushed(&self) -> Option<&RuleAttempt> {
+        self.attempts
+            .iter()
+            .find(|attempt| matches!(attempt.outcome, Outcome::Pushed { .. }))
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            let text = &self.source[step.range.range()];
+            let preview: String = text.chars().take(30).collect();
+            let ellipsis = if preview.len() < text.len() {
+                "..."
+            } else {
+                ""
+            };
+            writeln!(f, "{:?} {:?}{}", step.range.range(), preview, ellipsis)?;
+            for attempt in &step.attempts {
+                writeln!(f, "    {}: {}", attempt.name, attempt.outcome)?;
+            }
+            if step.pushed().is_none() {
+                writeln!(f, "    kept as text")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::NoMatch => write!(f, "no match"),
+            Outcome::MatchLimitExceeded => write!(f, "no match, limit exceeded"),
+            Outcome::Rejected(span) => write!(f, "matched {:?}, rejected", span.range()),
+            Outcome::Failed(span, error) => {
+                write!(f, "matched {:?}, failed: {}", span.range(), error)
+            }
+            Outcome::LimitExceeded(limit) => write!(f, "limit exceeded: {:?}", limit),
+            Outcome::Pushed {
+                span,
+                content,
+                is_terminal,
+                has_node,
+            } => {
+                let kind = if *is_terminal {
+                    "terminal"
+                } else {
+                    "nonterminal"
+                };
+                let node = if *has_node { "" } else { " without a node" };
+                write!(
+                    f,
+                    "matched {:?}, pushed {}{} with content {:?}",
+                    span.range(),
+                    kind,
+                    node,
+                    content.range()
+                )
+            }
+        }
+    }
+}
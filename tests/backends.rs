@@ -0,0 +1,160 @@
+//! Every regex engine has to parse messages the same way, so switching
+//! engines doesn't change any tree. The messages in `data/messages.txt` (one
+//! per line, `\n` standing for a line break) and pairs of snippets joined
+//! together go through the built in rules and the rules on each enabled
+//! engine. Build with several engines to compare them, e.g.
+//! `cargo test --features pcre,fancy`.
+
+use simple_ast::markdown_rules::{embed_description, embed_description_with};
+use simple_ast::regex::LimitExceeded;
+use simple_ast::{MarkdownNode, Parser, RuleSet};
+use std::collections::BTreeMap;
+
+// Snippets that start or end the rules, or that engines might read
+// differently: Unicode letters, digits and spaces, and words next to `_`
+const SNIPPETS: &[&str] = &[
+    "a",
+    "é",
+    "日本",
+    "😀",
+    "١٢",
+    "²",
+    "_",
+    "__",
+    "*",
+    "**",
+    "~~",
+    "||",
+    "`",
+    "```",
+    "> ",
+    ">>> ",
+    "\\",
+    "\n",
+    "  \n",
+    "\u{a0}",
+    "\u{2003}",
+    "\u{200b}",
+    "\u{301}",
+    "<@1>",
+    "<@١>",
+    "<#2>",
+    "<:e:3>",
+    "<t:4:R>",
+    "[a](https://b.c)",
+    "http://x",
+    "word:",
+    "a_b",
+    "x_",
+    "_x",
+];
+
+fn corpus() -> Vec<String> {
+    let mut messages: Vec<String> = include_str!("data/messages.txt")
+        .lines()
+        .map(|line| line.replace("\\n", "\n"))
+        .collect();
+    for first in SNIPPETS {
+        for second in SNIPPETS {
+            messages.push(format!("{}{}", first, second));
+            messages.push(format!("{}x{}y{}", first, second, first));
+        }
+    }
+    messages
+}
+
+fn rule_sets() -> Vec<(&'static str, RuleSet<MarkdownNode>)> {
+    #[allow(unused_mut)]
+    let mut sets = vec![("built in", embed_description())];
+    #[cfg(feature = "onig")]
+    sets.push((
+        "oniguruma",
+        embed_description_with::<simple_ast::regex::Oniguruma, ()>(),
+    ));
+    #[cfg(feature = "pcre")]
+    sets.push((
+        "pcre2",
+        embed_description_with::<simple_ast::regex::Pcre2, ()>(),
+    ));
+    #[cfg(feature = "fancy")]
+    sets.push((
+        "fancy-regex",
+        embed_description_with::<simple_ast::regex::FancyRegex, ()>(),
+    ));
+    sets
+}
+
+// What a rule matched at an offset, in a form that can be compared
+fn matched(rules: &RuleSet<MarkdownNode>, name: &str, message: &str, start: usize) -> String {
+    let rule = rules.get(name).unwrap();
+    match rule.captures_at(message, start, message.len()) {
+        Ok(Some(captures)) => {
+            let groups: Vec<_> = captures
+                .iter()
+                .map(|group| group.map(|group| (group.start, group.end)))
+                .collect();
+            format!("{:?}", groups)
+        }
+        Ok(None) => "no match".to_owned(),
+        Err(LimitExceeded) => "limit exceeded".to_owned(),
+    }
+}
+
+#[test]
+fn patterns_match_the_same() {
+    let sets = rule_sets();
+    let (_, first) = &sets[0];
+    // How often each rule differs, and the first message it differs on
+    let mut differences: BTreeMap<String, (usize, String)> = BTreeMap::new();
+    for message in corpus() {
+        for name in first.names() {
+            let first_bytes = first.get(name).unwrap().first_bytes();
+            for (start, _) in message.char_indices() {
+                let byte = message.as_bytes()[start];
+                if first_bytes.is_some_and(|bytes| !bytes.contains(&byte)) {
+                    continue;
+                }
+                let results: Vec<_> = sets
+                    .iter()
+                    .map(|(engine, rules)| (*engine, matched(rules, name, &message, start)))
+                    .collect();
+                if results.iter().any(|(_, result)| *result != results[0].1) {
+                    let difference = differences.entry(name.to_owned()).or_insert_with(|| {
+                        (0, format!("at {} in {:?}: {:?}", start, message, results))
+                    });
+                    difference.0 += 1;
+                }
+            }
+        }
+    }
+    let report: Vec<_> = differences
+        .iter()
+        .map(|(name, (count, first))| format!("{}: {} times, first {}", name, count, first))
+        .collect();
+    assert!(
+        report.is_empty(),
+        "patterns that differ between engines:\n{}",
+        report.join("\n")
+    );
+}
+
+#[test]
+fn trees_are_the_same() {
+    let parsers: Vec<_> = rule_sets()
+        .into_iter()
+        .map(|(engine, rules)| (engine, Parser::with_rule_set(rules)))
+        .collect();
+    for message in corpus() {
+        let trees: Vec<_> = parsers
+            .iter()
+            .map(|(engine, parser)| (*engine, format!("{:?}", parser.parse(&message).nodes)))
+            .collect();
+        for (engine, tree) in &trees[1..] {
+            assert_eq!(
+                tree, &trees[0].1,
+                "{} and {} parse {:?} differently",
+                engine, trees[0].0, message
+            );
+        }
+    }
+}
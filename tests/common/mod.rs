@@ -0,0 +1,19 @@
+//! Helpers shared by the integration tests
+
+use simple_ast::{MarkdownNode, Node, Spanned};
+
+// A compact view of a tree, e.g. `Bold[Text("a")]`
+pub fn outline(nodes: &[Spanned<MarkdownNode>]) -> String {
+    let parts: Vec<String> = nodes
+        .iter()
+        .map(|node| match node.node.get_children() {
+            Some(children) => {
+                let debug = format!("{:?}", node.node);
+                let name = &debug[..debug.find('(').unwrap_or(debug.len())];
+                format!("{}[{}]", name, outline(children))
+            }
+            None => format!("{:?}", node.node),
+        })
+        .collect();
+    parts.join(", ")
+}
@@ -0,0 +1,82 @@
+//! The pull API: events come in document order, nonterminals enclosing
+//! everything up to their `End`
+
+use simple_ast::markdown_rules::message_content;
+use simple_ast::{Event, MarkdownNode, Parser};
+
+fn events(parser: &Parser<MarkdownNode>, src: &str) -> Vec<String> {
+    parser
+        .events(src)
+        .map(|event| match event {
+            Event::Start(node) => format!("start {:?} {:?}", node.node, node.span.range()),
+            Event::Text(text) => format!("text {:?}", text.node),
+            Event::Leaf(node) => format!("leaf {:?}", node.node),
+            Event::End(span) => format!("end {:?}", span.range()),
+        })
+        .collect()
+}
+
+#[test]
+fn order_and_nesting() {
+    assert_eq!(
+        events(
+            &Parser::with_rule_set(message_content()),
+            "a **b _c_** <@1>\n> q"
+        ),
+        [
+            r#"leaf Text("a ")"#,
+            "start Bold([]) 2..11",
+            r#"leaf Text("b ")"#,
+            "start Italic([]) 6..9",
+            r#"leaf Text("c")"#,
+            "end 6..9",
+            "end 2..11",
+            r#"leaf Text(" ")"#,
+            "leaf UserMention(1)",
+            r#"leaf Text("\n")"#,
+            "start SingleBlockQuote([]) 17..20",
+            r#"leaf Text("q")"#,
+            "end 17..20",
+        ]
+    );
+}
+
+#[test]
+fn unmatched_text() {
+    // Text no rule matched comes as `Text` events
+    let mut rules = message_content();
+    rules.disable("text").unwrap();
+    assert_eq!(
+        events(&Parser::with_rule_set(rules), "a **b** c"),
+        [
+            r#"text "a ""#,
+            "start Bold([]) 2..7",
+            r#"text "b""#,
+            "end 2..7",
+            r#"text " c""#,
+        ]
+    );
+}
+
+#[test]
+fn events_build_the_same_tree() {
+    let parser: Parser<MarkdownNode> = Parser::with_rule_set(message_content());
+    let src = "**a __b__** ||c|| `d`\n>>> e";
+    let mut depth = 0usize;
+    let mut top_level = 0;
+    for event in parser.events(src) {
+        match event {
+            Event::Start(_) => {
+                if depth == 0 {
+                    top_level += 1;
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth -= 1,
+            Event::Text(_) | Event::Leaf(_) if depth == 0 => top_level += 1,
+            _ => {}
+        }
+    }
+    assert_eq!(depth, 0);
+    assert_eq!(top_level, parser.parse(src).nodes.len());
+}
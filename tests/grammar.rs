@@ -0,0 +1,119 @@
+//! Loading rules from grammar descriptions
+#![cfg(feature = "grammar")]
+
+use simple_ast::grammar::{Grammar, GrammarError, GrammarNode};
+use simple_ast::{Parser, Spanned};
+
+// A compact view of a tree, e.g. `shout[text("a")]`
+fn outline(nodes: &[Spanned<GrammarNode>]) -> String {
+    let parts: Vec<String> = nodes
+        .iter()
+        .map(|node| match &node.text {
+            Some(text) => format!("{}({:?})", node.kind, text),
+            None => format!("{}[{}]", node.kind, outline(&node.children)),
+        })
+        .collect();
+    parts.join(", ")
+}
+
+fn parse(grammar: &Grammar, src: &str) -> String {
+    let parser: Parser<GrammarNode> = Parser::with_rule_set(grammar.rule_set().unwrap());
+    outline(&parser.parse(src).nodes)
+}
+
+#[test]
+fn toml() {
+    let grammar = Grammar::from_toml(
+        r#"
+[[rules]]
+name = "tag"
+pattern = '\{\{(\w+)\}\}'
+kind = "tag"
+content = 1
+terminal = true
+
+[[rules]]
+name = "shout"
+pattern = '!!(.+?)!!'
+kind = "shout"
+content = 1
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        parse(&grammar, "hi {{name}} !!a {{b}}!!"),
+        r#"text("hi "), tag("name"), text(" "), shout[text("a "), tag("b")]"#
+    );
+}
+
+#[test]
+fn json() {
+    let grammar = Grammar::from_json(
+        r#"{"rules": [
+            {"name": "number", "pattern": "[0-9]+", "kind": "number", "terminal": true},
+            {"name": "group", "pattern": "\\((.*?)\\)", "kind": "group", "content": 1}
+        ]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        parse(&grammar, "(1 2) 3"),
+        r#"group[number("1"), text(" "), number("2")], text(" "), number("3")"#
+    );
+}
+
+#[test]
+fn any_kind_of_node() {
+    // Kinds aren't a fixed list, the nodes just carry them
+    let grammar = Grammar::from_toml(
+        r#"
+[[rules]]
+name = "made up"
+pattern = '~[a-z]+'
+kind = "no such kind"
+terminal = true
+"#,
+    )
+    .unwrap();
+    assert_eq!(parse(&grammar, "~abc"), r#"no such kind("~abc")"#);
+}
+
+#[test]
+fn invalid_grammars() {
+    assert!(matches!(
+        Grammar::from_toml("[[rules]]\nname = 1"),
+        Err(GrammarError::Toml(_))
+    ));
+    assert!(matches!(
+        Grammar::from_json(r#"{"rules": [{"name": "a"}]}"#),
+        Err(GrammarError::Json(_))
+    ));
+}
+
+#[test]
+fn invalid_pattern() {
+    let grammar = Grammar::from_json(
+        r#"{"rules": [{"name": "broken", "pattern": "(a", "kind": "k", "terminal": true}]}"#,
+    )
+    .unwrap();
+    match grammar.rule_set::<()>() {
+        Err(GrammarError::Pattern { rule, .. }) => assert_eq!(rule, "broken"),
+        _ => panic!("the pattern shouldn't compile"),
+    }
+}
+
+#[test]
+fn nonterminals_need_a_content_group() {
+    let grammar = Grammar::from_toml(
+        r#"
+[[rules]]
+name = "everything"
+pattern = '.+'
+kind = "all"
+"#,
+    )
+    .unwrap();
+    match grammar.rule_set::<()>() {
+        Err(GrammarError::WholeMatchContent { rule }) => assert_eq!(rule, "everything"),
+        _ => panic!("the rule's content is its whole match"),
+    }
+}
@@ -0,0 +1,180 @@
+//! `Parser::reparse` has to give the same tree as parsing the edited source
+//! from scratch
+
+use simple_ast::markdown_rules::message_content;
+use simple_ast::regex::{Captures, LimitExceeded};
+use simple_ast::{
+    Edit, IncrementalParse, MarkdownNode, MatchContext, ParseSpec, Parser, Rule, RuleError,
+    RuleSet, Spanned,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const MESSAGE: &str = "**bold** and *italic* <@1>\n> quote `code`\n||spoiler|| ~~strike~~ __under__\nplain text at the end";
+
+// Applies each edit in turn, checking every reparse against a fresh parse
+fn check_edits(parser: &Parser<MarkdownNode>, src: &str, edits: &[(usize, usize, &str)]) {
+    let mut src = src.to_owned();
+    let mut parse: IncrementalParse<MarkdownNode> = parser.parse_incremental(&src);
+    for &(start, end, text) in edits {
+        let mut edited = src.clone();
+        edited.replace_range(start..end, text);
+        parse = parser.reparse(&parse, &edited, Edit::replace(start..end, text));
+        assert_eq!(
+            parse.styled(),
+            &parser.parse(&edited),
+            "replacing {:?} in {:?} with {:?}",
+            start..end,
+            src,
+            text
+        );
+        src = edited;
+    }
+}
+
+#[test]
+fn insertions() {
+    let parser = Parser::with_rule_set(message_content());
+    let end = MESSAGE.len();
+    let middle = MESSAGE.find('>').unwrap();
+    check_edits(
+        &parser,
+        MESSAGE,
+        &[
+            (0, 0, "x"),
+            (0, 0, "**"),
+            (0, 0, "\n"),
+            (middle, middle, "*"),
+            (middle, middle, "a "),
+            (middle, middle, "\n"),
+            (end + 7, end + 7, "!"),
+            (end + 8, end + 8, " *a*"),
+            (end + 12, end + 12, "\n> "),
+        ],
+    );
+}
+
+#[test]
+fn deletions() {
+    let parser = Parser::with_rule_set(message_content());
+    let middle = MESSAGE.find('>').unwrap();
+    let end = MESSAGE.len();
+    check_edits(
+        &parser,
+        MESSAGE,
+        &[
+            (end - 3, end, ""),
+            (end - 10, end - 3, ""),
+            (middle, middle + 2, ""),
+            (middle - 1, middle, ""),
+            (0, 1, ""),
+            (0, 2, ""),
+        ],
+    );
+}
+
+#[test]
+fn edits_that_change_earlier_matches() {
+    let parser = Parser::with_rule_set(message_content());
+    // Closing a `**` from an earlier line makes it bold
+    check_edits(&parser, "**a b\nc d", &[(9, 9, "**"), (9, 11, "")]);
+    // A longer run of backticks closes `` ``` `` as a whole
+    check_edits(&parser, "```a` b\nc ``", &[(12, 12, "`"), (12, 13, "")]);
+    // Text stops before a URL scheme
+    check_edits(&parser, "see http:x", &[(8, 9, ""), (8, 8, ":")]);
+}
+
+// A rule counting how often it's matched
+struct Counted {
+    rule: Arc<dyn Rule<MarkdownNode>>,
+    attempts: Arc<AtomicUsize>,
+}
+
+impl Rule<MarkdownNode> for Counted {
+    fn accept_match(&self, context: &MatchContext<MarkdownNode>, state: &()) -> bool {
+        self.rule.accept_match(context, state)
+    }
+
+    fn allows_nesting(&self) -> bool {
+        self.rule.allows_nesting()
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        self.rule.first_bytes()
+    }
+
+    fn parse(
+        &self,
+        captures: &Captures,
+        state: &mut (),
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        self.rule.parse(captures, state)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        self.rule.captures(src)
+    }
+
+    fn captures_at<'a>(
+        &self,
+        src: &'a str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<Captures<'a>>, LimitExceeded> {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+        self.rule.captures_at(src, start, end)
+    }
+
+    fn reach(&self, src: &str, start: usize, match_end: Option<usize>, end: usize) -> usize {
+        self.rule.reach(src, start, match_end, end)
+    }
+
+    fn finish(&self, node: &mut Spanned<MarkdownNode>, src: &str) {
+        self.rule.finish(node, src)
+    }
+}
+
+// The message content rules, counting how often any of them is matched
+fn counted_rules() -> (Parser<MarkdownNode>, Arc<AtomicUsize>) {
+    let mut rules = message_content();
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let names: Vec<String> = rules.names().map(str::to_owned).collect();
+    let mut counted = RuleSet::new();
+    for name in names {
+        let rule = rules.remove(&name).unwrap();
+        let attempts = attempts.clone();
+        counted.push(name, Counted { rule, attempts }).unwrap();
+    }
+    (Parser::with_rule_set(counted), attempts)
+}
+
+#[test]
+fn reparses_only_match_around_the_edit() {
+    let (parser, attempts) = counted_rules();
+    let message = "hey, **did** you see <@123>? `cargo bench` is _way_ faster ||now||\n".repeat(60);
+    // The whole message in one quote too, with the edit inside it
+    let quoted = format!(">>> {}", message);
+    for src in &[message, quoted] {
+        let parse = parser.parse_incremental(src);
+        let full = attempts.swap(0, Ordering::Relaxed);
+        // Inside a word, where an edit only changes the text around it
+        let middle = src[..src.len() / 2].rfind("hey").unwrap() + 1;
+        let content = src.find("hey").unwrap();
+        for &at in &[content, content + 3, middle, src.len()] {
+            let mut edited = src.clone();
+            edited.insert(at, 'x');
+            let reparsed = parser.reparse(&parse, &edited, Edit::replace(at..at, "x"));
+            let reparse = attempts.swap(0, Ordering::Relaxed);
+            assert_eq!(reparsed.styled(), &parser.parse(&edited));
+            attempts.store(0, Ordering::Relaxed);
+            assert!(
+                reparse * 50 < full,
+                "{} rules matched reparsing after {} into {:?}, {} parsing it",
+                reparse,
+                at,
+                &src[..20],
+                full
+            );
+        }
+    }
+}
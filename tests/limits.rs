@@ -0,0 +1,102 @@
+//! Every `Limit` stops the parse, keeping the rest of the source as text
+
+mod common;
+
+use common::outline;
+use simple_ast::markdown_rules::message_content;
+use simple_ast::matcher::{function, literal, MatcherRule};
+use simple_ast::regex::Captures;
+use simple_ast::{Limit, MarkdownNode, ParseLimits, ParseSpec, Parser, RuleError, RuleSet};
+
+fn limited(limits: ParseLimits, src: &str) -> (String, Option<Limit>) {
+    let parser: Parser<MarkdownNode> = Parser::with_rule_set(message_content()).with_limits(limits);
+    let styled = parser.parse(src);
+    (outline(&styled.nodes), styled.limit_exceeded)
+}
+
+#[test]
+fn depth() {
+    assert_eq!(
+        limited(ParseLimits::new().max_depth(2), "**__~~a~~__**"),
+        (
+            r#"Bold[Underline[Text("~~a~~")]]"#.to_owned(),
+            Some(Limit::Depth)
+        )
+    );
+    assert_eq!(
+        limited(ParseLimits::new().max_depth(3), "**__~~a~~__**").1,
+        None
+    );
+}
+
+#[test]
+fn nodes() {
+    assert_eq!(
+        limited(ParseLimits::new().max_nodes(2), "**a** b *c*"),
+        (
+            r#"Bold[Text("a")], Text(" b *c*")"#.to_owned(),
+            Some(Limit::Nodes)
+        )
+    );
+}
+
+#[test]
+fn rule_attempts() {
+    assert_eq!(
+        limited(ParseLimits::new().max_rule_attempts(3), "**a** b *c*"),
+        (
+            r#"Bold[Text("a")], Text(" b *c*")"#.to_owned(),
+            Some(Limit::RuleAttempts)
+        )
+    );
+}
+
+#[test]
+fn empty_matches_make_no_progress() {
+    let rules: RuleSet<MarkdownNode> = RuleSet::new().with(
+        "empty",
+        MatcherRule::new(literal(""), |captures: &Captures, _: &mut ()| {
+            let (start, end) = captures.try_pos(0)?;
+            Ok(ParseSpec::create_terminal(None, start, end))
+        }),
+    );
+    let styled = Parser::with_rule_set(rules).parse("abc");
+    assert_eq!(outline(&styled.nodes), r#"Text("abc")"#);
+    assert_eq!(styled.limit_exceeded, Some(Limit::NoProgress));
+}
+
+// Opens a node around everything that's left, for whichever of the two rules
+// `state` says is next
+fn wrap_all(
+    next: bool,
+    node: fn() -> MarkdownNode,
+) -> impl Fn(&Captures, &mut bool) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+    move |captures: &Captures, state: &mut bool| {
+        if *state != next {
+            return Err(RuleError::Declined);
+        }
+        *state = !next;
+        let (start, end) = captures.try_pos(0)?;
+        Ok(ParseSpec::create_nonterminal(Some(node()), start, end))
+    }
+}
+
+#[test]
+fn rules_taking_turns_make_no_progress() {
+    let everything = || function(|rest: &str| Some(rest.len()));
+    let rules: RuleSet<MarkdownNode, bool> = RuleSet::new()
+        .with(
+            "bold",
+            MatcherRule::new(everything(), wrap_all(false, || MarkdownNode::Bold(vec![]))),
+        )
+        .with(
+            "italic",
+            MatcherRule::new(
+                everything(),
+                wrap_all(true, || MarkdownNode::Italic(vec![])),
+            ),
+        );
+    let styled = Parser::with_rule_set(rules).parse_with_state("abc", false);
+    assert_eq!(styled.limit_exceeded, Some(Limit::NoProgress));
+    assert_eq!(outline(&styled.nodes), r#"Bold[Italic[Text("abc")]]"#);
+}
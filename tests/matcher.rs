@@ -0,0 +1,130 @@
+//! Rules built from matchers instead of regexes
+
+mod common;
+
+use common::outline;
+use simple_ast::markdown_rules::message_content;
+use simple_ast::matcher::{
+    alternation, capture, delimited, function, literal, sequence, Matcher, MatcherRule,
+};
+use simple_ast::regex::Captures;
+use simple_ast::{MarkdownNode, ParseSpec, Parser};
+
+// The groups a matcher finds at the start of `src`
+fn groups(matcher: &impl Matcher, src: &str) -> Option<Vec<Option<(usize, usize)>>> {
+    matcher.captures(src).map(|captures| {
+        captures
+            .iter()
+            .map(|group| group.map(|group| (group.start, group.end)))
+            .collect()
+    })
+}
+
+#[test]
+fn literals() {
+    let matcher = literal("<<");
+    assert_eq!(groups(&matcher, "<<a"), Some(vec![Some((0, 2))]));
+    assert_eq!(groups(&matcher, "a<<"), None);
+    assert_eq!(matcher.first_bytes(), Some(vec![b'<']));
+}
+
+#[test]
+fn sequences() {
+    let digits = || {
+        function(|rest: &str| {
+            rest.find(|c: char| !c.is_ascii_digit())
+                .or(Some(rest.len()))
+                .filter(|&len| len > 0)
+        })
+    };
+    let matcher = sequence(vec![
+        Box::new(literal("#")),
+        Box::new(capture(digits())),
+        Box::new(literal(".")),
+    ]);
+    assert_eq!(
+        groups(&matcher, "#12."),
+        Some(vec![Some((0, 4)), Some((1, 3))])
+    );
+    assert_eq!(groups(&matcher, "#12"), None);
+    assert_eq!(groups(&matcher, "#."), None);
+    assert_eq!(matcher.first_bytes(), Some(vec![b'#']));
+    // `then` is the same for two matchers
+    let matcher = literal("#").then(capture(digits()));
+    assert_eq!(
+        groups(&matcher, "#7"),
+        Some(vec![Some((0, 2)), Some((1, 2))])
+    );
+}
+
+#[test]
+fn alternations() {
+    let matcher = alternation(vec![
+        Box::new(capture(literal("ab"))),
+        Box::new(literal("a")),
+    ]);
+    assert_eq!(
+        groups(&matcher, "abc"),
+        Some(vec![Some((0, 2)), Some((0, 2))])
+    );
+    // The first option's group doesn't linger once it fails
+    assert_eq!(groups(&matcher, "ac"), Some(vec![Some((0, 1))]));
+    assert_eq!(groups(&matcher, "c"), None);
+    assert_eq!(matcher.first_bytes(), Some(vec![b'a', b'a']));
+    assert_eq!(
+        groups(&literal("x").or(literal("y")), "y"),
+        Some(vec![Some((0, 1))])
+    );
+}
+
+#[test]
+fn delimiters() {
+    let matcher = delimited("==", "==");
+    assert_eq!(
+        groups(&matcher, "==a b==c"),
+        Some(vec![Some((0, 7)), Some((2, 5))])
+    );
+    assert_eq!(groups(&matcher, "===="), None);
+    let matcher = delimited("==", "==").no_whitespace();
+    assert_eq!(
+        groups(&matcher, "==ab=="),
+        Some(vec![Some((0, 6)), Some((2, 4))])
+    );
+    assert_eq!(groups(&matcher, "==a b=="), None);
+}
+
+#[test]
+fn matching_inside_the_source() {
+    // Positions index the whole source and the match stops at `end`
+    let matcher = delimited("[", "]");
+    let captures = matcher.captures_at("ab[c]", 2, 5).unwrap();
+    assert_eq!(captures.pos(1), Some((3, 4)));
+    assert!(matcher.captures_at("ab[c]", 2, 4).is_none());
+}
+
+#[test]
+fn matcher_rules() {
+    let mut rules = message_content();
+    rules
+        .insert_before(
+            "bold",
+            "highlight",
+            MatcherRule::new(
+                delimited("==", "==").no_whitespace(),
+                |captures: &Captures, _: &mut ()| {
+                    let (start, end) = captures.try_pos(1)?;
+                    Ok(ParseSpec::create_nonterminal(
+                        Some(MarkdownNode::Bold(Vec::new())),
+                        start,
+                        end,
+                    ))
+                },
+            ),
+        )
+        .unwrap();
+    let parser = Parser::with_rule_set(rules);
+    assert_eq!(
+        outline(&parser.parse("a ==b*c*== ==d e==").nodes),
+        r#"Text("a "), Bold[Text("b"), Italic[Text("c")]], Text(" "), Text("="), Text("=d e"), Text("="), Text("=")"#
+    );
+}
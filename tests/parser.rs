@@ -0,0 +1,569 @@
+//! How the parser turns rule matches into trees, using the markdown rules
+
+mod common;
+
+use common::outline;
+use simple_ast::markdown_rules::{message_content, Bold, Italic};
+use simple_ast::matcher::{function, literal, Matcher, MatcherRule};
+use simple_ast::regex::Captures;
+use simple_ast::{
+    Event, MarkdownNode, MatchContext, Node, Outcome, ParseError, ParseSpec, Parser, Rule,
+    RuleError, RuleSet, Span, Spanned, Styled,
+};
+use std::sync::{Arc, Mutex};
+
+fn parser() -> Parser<MarkdownNode> {
+    Parser::with_rule_set(message_content())
+}
+
+#[test]
+fn spans() {
+    let src = "a **b _c_** <@1>";
+    let nodes = parser().parse(src).nodes;
+    let spans: Vec<_> = nodes.iter().map(|node| (node.span, node.content)).collect();
+    // A node's span includes its delimiters, its content doesn't
+    assert_eq!(
+        spans,
+        [
+            (Span::new(0, 2), Span::new(0, 2)),
+            (Span::new(2, 11), Span::new(4, 9)),
+            (Span::new(11, 12), Span::new(11, 12)),
+            (Span::new(12, 16), Span::new(14, 15)),
+        ]
+    );
+    let italic = &nodes[1].get_children().unwrap()[1];
+    assert_eq!(&src[italic.span.range()], "_c_");
+    assert_eq!(&src[italic.content.range()], "c");
+    let text = &italic.get_children().unwrap()[0];
+    assert_eq!(
+        (text.span, text.content),
+        (Span::new(7, 8), Span::new(7, 8))
+    );
+}
+
+#[test]
+fn unmatched_text_is_kept() {
+    let parser = parser();
+    assert_eq!(
+        outline(&parser.parse("a ~~b *c").nodes),
+        r#"Text("a "), Text("~"), Text("~b "), Text("*c")"#
+    );
+    for src in ["a ~~b *c", "**", "<@ > <t:x>", "||a|| `b", "é\\"] {
+        let styled = parser.parse(src);
+        assert_eq!(styled.as_markdown(), src);
+        assert_eq!(styled.lost_ranges(src), []);
+    }
+}
+
+#[test]
+fn lost_ranges() {
+    // A rule that consumes `%%` without making a node for it
+    let mut rules = message_content();
+    rules
+        .insert_before(
+            "text",
+            "hidden",
+            MatcherRule::new(literal("%%"), |captures: &Captures, _: &mut ()| {
+                let (start, end) = captures.try_pos(0)?;
+                Ok(ParseSpec::create_terminal(None, start, end))
+            }),
+        )
+        .unwrap();
+    let parser = Parser::with_rule_set(rules);
+    let src = "a %%**b%%**%%";
+    let styled = parser.parse(src);
+    assert_eq!(outline(&styled.nodes), r#"Text("a "), Bold[Text("b")]"#);
+    assert_eq!(
+        styled.lost_ranges(src),
+        [Span::new(2, 4), Span::new(7, 9), Span::new(11, 13)]
+    );
+}
+
+#[test]
+fn fragments_see_the_text_around_them() {
+    let parser = parser();
+    // `_var_` alone is italic, but not after the rest of the word
+    let src = "my_var_ a";
+    let styled = parser.parse_fragment(src, Span::new(2, 7));
+    assert_eq!(outline(&styled.nodes), r#"Text("_var"), Text("_")"#);
+    assert_eq!(styled.nodes[0].span, Span::new(2, 6));
+    // A quote has to start a line
+    let src = "a > b";
+    assert_eq!(
+        outline(&parser.parse_fragment(src, Span::new(2, 5)).nodes),
+        r#"Text("> b")"#
+    );
+    let src = "a\n> b";
+    assert_eq!(
+        outline(&parser.parse_fragment(src, Span::new(2, 5)).nodes),
+        r#"SingleBlockQuote[Text("b")]"#
+    );
+    // Nothing past the end of the range is matched
+    let src = "**a** b";
+    assert_eq!(
+        outline(&parser.parse_fragment(src, Span::new(0, 4)).nodes),
+        r#"Text("*"), Italic[Text("a")]"#
+    );
+}
+
+// A page with markdown between braces, parsed by a parser of its own
+#[derive(Debug, Clone, PartialEq)]
+enum Page {
+    Text(String),
+    Braces(Vec<Spanned<Page>>),
+    Markdown(Vec<Spanned<MarkdownNode>>),
+}
+
+impl Node<Page> for Page {
+    fn get_children(&self) -> Option<&[Spanned<Page>]> {
+        match self {
+            Page::Braces(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    fn get_children_mut(&mut self) -> Option<&mut Vec<Spanned<Page>>> {
+        match self {
+            Page::Braces(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    fn add_child(&mut self, child: Spanned<Page>) {
+        if let Page::Braces(children) = self {
+            children.push(child);
+        }
+    }
+
+    fn from_text(text: &str) -> Page {
+        Page::Text(text.to_owned())
+    }
+}
+
+struct Embedded(Parser<MarkdownNode>);
+
+impl Rule<Page> for Embedded {
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"{")
+    }
+
+    fn parse(&self, captures: &Captures, _state: &mut ()) -> Result<ParseSpec<Page>, RuleError> {
+        let (start, end) = captures.try_pos(0)?;
+        Ok(ParseSpec::create_nonterminal(
+            Some(Page::Braces(Vec::new())),
+            start + 1,
+            end - 1,
+        ))
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        function(|rest: &str| {
+            if !rest.starts_with('{') {
+                return None;
+            }
+            rest.find('}').map(|end| end + 1)
+        })
+        .captures(src)
+    }
+
+    fn finish(&self, node: &mut Spanned<Page>, src: &str) {
+        let nodes = self.0.parse_fragment(src, node.content).nodes;
+        node.node = Page::Markdown(nodes);
+    }
+}
+
+#[test]
+fn fragments_can_be_parsed_while_finishing_another_tree() {
+    let rules = RuleSet::new().with("embedded", Embedded(parser()));
+    let src = "a {**b** _c_} d";
+    let nodes = Parser::with_rule_set(rules).parse(src).nodes;
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(nodes[0].node, Page::Text("a ".to_owned()));
+    assert_eq!(nodes[1].span, Span::new(2, 13));
+    let markdown = match &nodes[1].node {
+        Page::Markdown(markdown) => markdown,
+        node => panic!("expected markdown, got {:?}", node),
+    };
+    assert_eq!(
+        outline(markdown),
+        r#"Bold[Text("b")], Text(" "), Italic[Text("c")]"#
+    );
+    // Spans point into the whole source
+    assert_eq!(&src[markdown[2].span.range()], "_c_");
+    assert_eq!(nodes[2].node, Page::Text(" d".to_owned()));
+}
+
+#[test]
+fn ids_too_large_fall_back_to_text() {
+    let parser = parser();
+    assert_eq!(
+        outline(&parser.parse("hi <@99999999999999999999999>").nodes),
+        r#"Text("hi "), Text("<"), Text("@99999999999999999999999"), Text(">")"#
+    );
+    assert_eq!(
+        outline(&parser.parse("<#99999999999999999999999>").nodes),
+        r##"Text("<"), Text("#99999999999999999999999"), Text(">")"##
+    );
+}
+
+#[test]
+fn try_parse_stops_at_the_failing_rule() {
+    let parser = parser();
+    assert!(matches!(
+        parser.try_parse("hi <@99999999999999999999999>"),
+        Err(ParseError {
+            position: 3,
+            error: RuleError::Invalid(_),
+        })
+    ));
+    // Declining isn't an error
+    assert!(parser.try_parse("hi <@1> **a**").is_ok());
+}
+
+#[test]
+fn quotes_in_every_parse() {
+    // Quotes used to be turned down once the rules had seen one
+    let parser = parser();
+    for _ in 0..2 {
+        assert_eq!(
+            outline(&parser.parse("> a").nodes),
+            r#"SingleBlockQuote[Text("a")]"#
+        );
+    }
+}
+
+// A rule that counts how deep it's nested in the parse state
+struct Deeper<R>(R);
+
+impl<R: Rule<MarkdownNode, usize>> Rule<MarkdownNode, usize> for Deeper<R> {
+    fn accept_match(&self, context: &MatchContext<MarkdownNode>, depth: &usize) -> bool {
+        self.0.accept_match(context, depth)
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        self.0.first_bytes()
+    }
+
+    fn parse(
+        &self,
+        captures: &Captures,
+        depth: &mut usize,
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        let spec = self.0.parse(captures, depth)?;
+        *depth += 1;
+        Ok(spec)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        self.0.captures(src)
+    }
+}
+
+#[test]
+fn state_is_rolled_back_when_a_node_closes() {
+    let rules: RuleSet<MarkdownNode, usize> = RuleSet::new()
+        .with("bold", Deeper(Bold))
+        .with("italic", Deeper(Italic))
+        .with(
+            "depth",
+            MatcherRule::new(literal("#"), |captures: &Captures, depth: &mut usize| {
+                let (start, end) = captures.try_pos(0)?;
+                let text = format!("#{}", depth);
+                Ok(ParseSpec::create_terminal(
+                    Some(MarkdownNode::Text(text)),
+                    start,
+                    end,
+                ))
+            }),
+        );
+    let parser = Parser::with_rule_set(rules);
+    let src = "#**#_#_#**#";
+    let expected = r##"Text("#0"), Bold[Text("#1"), Italic[Text("#2")], Text("#1")], Text("#0")"##;
+    assert_eq!(outline(&parser.parse(src).nodes), expected);
+    // Every parse starts from the state it's given
+    assert_eq!(outline(&parser.parse(src).nodes), expected);
+    assert_eq!(
+        outline(&parser.parse_with_state(src, 5).nodes),
+        r##"Text("#5"), Bold[Text("#6"), Italic[Text("#7")], Text("#6")], Text("#5")"##
+    );
+}
+
+// Bold that merges its text and turns back into plain text if there's only
+// whitespace in it
+struct TidyBold;
+
+impl Rule<MarkdownNode> for TidyBold {
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Rule::<MarkdownNode>::first_bytes(&Bold)
+    }
+
+    fn parse(
+        &self,
+        captures: &Captures,
+        state: &mut (),
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        Bold.parse(captures, state)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        Rule::<MarkdownNode>::captures(&Bold, src)
+    }
+
+    fn finish(&self, node: &mut Spanned<MarkdownNode>, src: &str) {
+        if src[node.content.range()].trim().is_empty() {
+            node.node = MarkdownNode::Text(src[node.span.range()].to_owned());
+            return;
+        }
+        let children = node.get_children_mut().unwrap();
+        let mut merged: Vec<Spanned<MarkdownNode>> = Vec::new();
+        for child in children.drain(..) {
+            if let (Some(last), MarkdownNode::Text(text)) = (merged.last_mut(), &child.node) {
+                if let MarkdownNode::Text(last_text) = &mut last.node {
+                    last_text.push_str(text);
+                    last.span.end = child.span.end;
+                    last.content.end = child.content.end;
+                    continue;
+                }
+            }
+            merged.push(child);
+        }
+        *children = merged;
+    }
+}
+
+#[test]
+fn finish() {
+    let mut rules = message_content();
+    rules.remove("bold").unwrap();
+    rules.insert_before("underline", "bold", TidyBold).unwrap();
+    let parser = Parser::with_rule_set(rules);
+    assert_eq!(
+        outline(&parser.parse("**a*b** **  ** **c _d_**").nodes),
+        r#"Bold[Text("a*b")], Text(" "), Text("**  **"), Text(" "), Bold[Text("c "), Italic[Text("d")]]"#
+    );
+    // Finished nodes keep their spans
+    let nodes = parser.parse("x **a*b**").nodes;
+    assert_eq!(nodes[1].get_children().unwrap()[0].span, Span::new(4, 7));
+    // The event stream hands nodes out before they're finished
+    let text_events = parser
+        .events("**a*b**")
+        .filter(|event| matches!(event, Event::Leaf(_)))
+        .count();
+    assert_eq!(text_events, 2);
+}
+
+// Turns down every `%`, writing down what `accept_match` was told about it
+struct Percent(Arc<Mutex<Vec<String>>>);
+
+impl Rule<MarkdownNode> for Percent {
+    fn accept_match(&self, context: &MatchContext<MarkdownNode>, _state: &()) -> bool {
+        let previous = context
+            .previous_sibling
+            .map(|node| outline(std::slice::from_ref(node)));
+        let parent = context
+            .parent()
+            .map(|node| outline(std::slice::from_ref(node)));
+        self.0.lock().unwrap().push(format!(
+            "previous {:?}, parent {:?}, {} ancestors, at line start: {}",
+            previous,
+            parent,
+            context.ancestors.len(),
+            context.at_line_start
+        ));
+        false
+    }
+
+    fn first_bytes(&self) -> Option<&[u8]> {
+        Some(b"%")
+    }
+
+    fn parse(
+        &self,
+        _captures: &Captures,
+        _state: &mut (),
+    ) -> Result<ParseSpec<MarkdownNode>, RuleError> {
+        Err(RuleError::Declined)
+    }
+
+    fn captures<'a>(&self, src: &'a str) -> Option<Captures<'a>> {
+        literal("%").captures(src)
+    }
+}
+
+// What the `%`s in `src` are told, with no rule for plain text
+fn match_contexts(src: &str) -> Vec<String> {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let rules = RuleSet::new()
+        .with("bold", Bold)
+        .with("italic", Italic)
+        .with("percent", Percent(seen.clone()));
+    Parser::with_rule_set(rules).parse(src);
+    let seen = seen.lock().unwrap();
+    seen.clone()
+}
+
+#[test]
+fn match_context() {
+    assert_eq!(
+        match_contexts("%"),
+        ["previous None, parent None, 0 ancestors, at line start: true"]
+    );
+    // Text no rule matched comes before the match as a node of its own
+    assert_eq!(
+        match_contexts("ab %"),
+        [r#"previous Some("Text(\"ab \")"), parent None, 0 ancestors, at line start: false"#]
+    );
+    assert_eq!(
+        match_contexts("**x** yz %"),
+        [r#"previous Some("Text(\" yz \")"), parent None, 0 ancestors, at line start: false"#]
+    );
+    assert_eq!(
+        match_contexts("**x**%"),
+        [r#"previous Some("Bold[]"), parent None, 0 ancestors, at line start: false"#]
+    );
+    assert_eq!(
+        match_contexts("**%**\n%"),
+        [
+            r#"previous None, parent Some("Bold[]"), 1 ancestors, at line start: false"#,
+            r#"previous Some("Text(\"\\n\")"), parent None, 0 ancestors, at line start: true"#,
+        ]
+    );
+    assert_eq!(
+        match_contexts("**_a\n%_**"),
+        [
+            r#"previous Some("Text(\"a\\n\")"), parent Some("Italic[]"), 2 ancestors, at line start: true"#
+        ]
+    );
+}
+
+#[test]
+fn trace() {
+    let rules = RuleSet::new()
+        .with("bold", Bold)
+        .with("percent", Percent(Arc::default()))
+        .with(
+            "tag",
+            MatcherRule::new(literal("#"), |_: &Captures, _: &mut ()| {
+                Err::<ParseSpec<MarkdownNode>, _>(RuleError::Invalid("no tags".to_owned()))
+            }),
+        );
+    let parser = Parser::with_rule_set(rules);
+    let src = "**a** *%#";
+    let (styled, trace) = parser.parse_traced(src);
+    assert_eq!(styled, parser.parse(src));
+    assert_eq!(trace.source, src);
+    // A step for every place rules were tried at, even if none could start
+    // there
+    let ranges: Vec<_> = trace.steps.iter().map(|step| step.range).collect();
+    assert_eq!(
+        ranges,
+        [
+            Span::new(0, 9),
+            Span::new(2, 3),
+            Span::new(5, 9),
+            Span::new(6, 9),
+            Span::new(7, 9),
+            Span::new(8, 9),
+        ]
+    );
+    let outcomes: Vec<Vec<_>> = trace
+        .steps
+        .iter()
+        .map(|step| {
+            step.attempts
+                .iter()
+                .map(|attempt| (attempt.rule, attempt.name.as_str(), attempt.outcome.clone()))
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        outcomes,
+        [
+            vec![(
+                0,
+                "bold",
+                Outcome::Pushed {
+                    span: Span::new(0, 5),
+                    content: Span::new(2, 3),
+                    is_terminal: false,
+                    has_node: true,
+                }
+            )],
+            vec![],
+            vec![],
+            vec![(0, "bold", Outcome::NoMatch)],
+            vec![(1, "percent", Outcome::Rejected(Span::new(7, 8)))],
+            vec![(
+                2,
+                "tag",
+                Outcome::Failed(Span::new(8, 9), RuleError::Invalid("no tags".to_owned()))
+            )],
+        ]
+    );
+    assert_eq!(trace.steps[0].pushed(), trace.steps[0].attempts.first());
+    assert!(trace.steps[1..].iter().all(|step| step.pushed().is_none()));
+    assert_eq!(
+        trace.to_string(),
+        r##"0..9 "**a** *%#"
+    bold: matched 0..5, pushed nonterminal with content 2..3
+2..3 "a"
+    kept as text
+5..9 " *%#"
+    kept as text
+6..9 "*%#"
+    bold: no match
+    kept as text
+7..9 "%#"
+    percent: matched 7..8, rejected
+    kept as text
+8..9 "#"
+    tag: matched 8..9, failed: no tags
+    kept as text
+"##
+    );
+}
+
+fn assert_send<T: Send>() {}
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn parsers_can_be_shared_between_threads() {
+    assert_send_sync::<Parser<MarkdownNode>>();
+    assert_send_sync::<Arc<Parser<MarkdownNode>>>();
+
+    let parser = Arc::new(parser());
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let parser = Arc::clone(&parser);
+            std::thread::spawn(move || parser.parse(&format!("**{}** > a", i)))
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        let styled = handle.join().unwrap();
+        assert_eq!(styled, parser.parse(&format!("**{}** > a", i)));
+    }
+}
+
+#[test]
+fn parse_many_keeps_the_order_of_the_sources() {
+    // Results have to be handed back from other threads
+    assert_send::<Styled<MarkdownNode>>();
+
+    let parser = parser();
+    let mut sources: Vec<String> = include_str!("data/messages.txt")
+        .lines()
+        .map(|line| line.replace("\\n", "\n"))
+        .collect();
+    // Sources that only differ in a number, so a result out of place shows
+    sources.extend((0..100).map(|i| format!("**{}** <@{}>\n> {}", i, i, "_a_ ".repeat(i % 5))));
+    assert!(sources.len() > 100);
+
+    let parsed = parser.parse_many(&sources);
+    let expected: Vec<_> = sources.iter().map(|src| parser.parse(src)).collect();
+    assert_eq!(parsed, expected);
+    // Borrowed sources work too, and no sources give no results
+    let borrowed: Vec<&str> = sources.iter().map(String::as_str).collect();
+    assert_eq!(parser.parse_many(&borrowed), expected);
+    assert!(parser.parse_many::<&str>(&[]).is_empty());
+}
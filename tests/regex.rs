@@ -0,0 +1,327 @@
+//! Behavior every regex engine has to share. The regex tests run for each
+//! engine that's enabled, the parsing tests use `DefaultEngine`.
+
+// Clippy mistakes these for the regex crate, which has no lookaround
+#![allow(clippy::invalid_regex)]
+
+mod common;
+
+use common::outline;
+use simple_ast::markdown_rules::{embed_description, message_content};
+use simple_ast::regex::{Group, LimitExceeded, Regex, RegexEngine, RegexOptions};
+use simple_ast::{MarkdownNode, Parser, RuleError};
+
+// Runs a test for every enabled engine
+macro_rules! engines {
+    ($($test:ident),*) => {$(
+        #[test]
+        fn $test() {
+            #[cfg(feature = "onig")]
+            cases::$test::<simple_ast::regex::Oniguruma>();
+            #[cfg(feature = "pcre")]
+            cases::$test::<simple_ast::regex::Pcre2>();
+            #[cfg(feature = "fancy")]
+            cases::$test::<simple_ast::regex::FancyRegex>();
+        }
+    )*};
+}
+
+engines!(
+    groups,
+    byte_offsets,
+    invalid_pattern,
+    lookaround,
+    backreference,
+    word_boundary,
+    named_groups,
+    mixed_groups,
+    group_iteration,
+    owned_captures,
+    offset_matching,
+    case_insensitive,
+    backtrack_limit,
+    unicode_classes
+);
+
+#[test]
+#[cfg(feature = "onig")]
+fn default_engine() {
+    use std::any::TypeId;
+    // Enabling another engine alongside doesn't move the built in rules
+    assert_eq!(
+        TypeId::of::<simple_ast::regex::DefaultEngine>(),
+        TypeId::of::<simple_ast::regex::Oniguruma>()
+    );
+}
+
+fn parse(src: &str) -> String {
+    let parser: Parser<MarkdownNode> = Parser::with_rule_set(message_content());
+    outline(&parser.parse(src).nodes)
+}
+
+#[test]
+fn bold_and_italics() {
+    assert_eq!(parse("**a**"), r#"Bold[Text("a")]"#);
+    assert_eq!(parse("*a*"), r#"Italic[Text("a")]"#);
+    assert_eq!(parse("_a_"), r#"Italic[Text("a")]"#);
+    assert_eq!(parse("***a***"), r#"Bold[Italic[Text("a")]]"#);
+    assert_eq!(
+        parse("*a **b** c*"),
+        r#"Italic[Text("a "), Bold[Text("b")], Text(" c")]"#
+    );
+    // Underscores inside a word don't start italics
+    assert_eq!(parse("a_b_c"), r#"Text("a"), Text("_b"), Text("_c")"#);
+    // The rule sees the word before the fragment it's matched against
+    assert_eq!(parse("my_var_"), r#"Text("my"), Text("_var"), Text("_")"#);
+    assert_eq!(parse("**a**_b_"), r#"Bold[Text("a")], Italic[Text("b")]"#);
+    assert_eq!(parse("* a*"), r#"Text("* a"), Text("*")"#);
+}
+
+#[test]
+fn word_characters() {
+    // Letters, marks, numbers and connectors are word characters on every
+    // engine
+    assert_eq!(parse("½_a_"), r#"Text("½"), Text("_a"), Text("_")"#);
+    assert_eq!(parse("①_a_"), r#"Text("①"), Text("_a"), Text("_")"#);
+    assert_eq!(parse("_a_①"), r#"Text("_a"), Text("_①")"#);
+    // So are the words before a `:` that text stops at, in case it's a URL
+    assert_eq!(
+        parse("say héllo:there now"),
+        r#"Text("say "), Text("h"), Text("é"), Text("l"), Text("l"), Text("o"), Text(":there now")"#
+    );
+    assert_eq!(parse("a ①:x"), r#"Text("a "), Text("①"), Text(":x")"#);
+}
+
+#[test]
+fn text() {
+    assert_eq!(parse("hello world"), r#"Text("hello world")"#);
+    assert_eq!(parse("héllo, wörld"), r#"Text("héllo"), Text(", wörld")"#);
+    // Text stops before anything that looks like a URL scheme
+    assert_eq!(
+        parse("see http://x"),
+        r#"Text("see "), Text("h"), Text("t"), Text("t"), Text("p"), Text(":"), Text("/"), Text("/x")"#
+    );
+}
+
+#[test]
+fn discord_rules() {
+    assert_eq!(
+        parse("<@1> <#2> <@&3> <:e:4>"),
+        r#"UserMention(1), Text(" "), ChannelMention(2), Text(" "), RoleMention(3), Text(" "), Emoji("e", 4)"#
+    );
+    assert_eq!(parse("``a`b``"), r#"InlineCode("a`b")"#);
+    assert_eq!(parse("```rs\nx\n```"), r#"Code("rs", "x")"#);
+    assert_eq!(
+        parse("> a\nb"),
+        r#"SingleBlockQuote[Text("a"), Text("\n")], Text("b")"#
+    );
+    assert_eq!(parse("<t:5:R>"), r#"Timestamp(5, Some('R'))"#);
+}
+
+#[test]
+fn masked_links() {
+    let parser: Parser<MarkdownNode> = Parser::with_rule_set(embed_description());
+    assert_eq!(
+        outline(&parser.parse("[**a** <@1>](https://x)").nodes),
+        r#"MaskedLink[Bold[Text("a")], Text(" "), Text("<"), Text("@1"), Text(">")]"#
+    );
+}
+
+mod cases {
+    use super::*;
+
+    pub fn groups<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"^(a)(b)?(c)").unwrap();
+        let captures = regex.captures("acd").unwrap();
+        assert_eq!(captures.pos(0), Some((0, 2)));
+        assert_eq!(captures.pos(1), Some((0, 1)));
+        assert_eq!(captures.pos(2), None);
+        assert_eq!(captures.at(3), Some("c"));
+        assert_eq!(captures.pos(9), None);
+        assert_eq!(captures.try_at(2), Err(RuleError::MissingGroup(2)));
+        assert!(regex.captures("bc").is_none());
+    }
+
+    pub fn byte_offsets<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"(é+)x").unwrap();
+        let captures = regex.captures("aééx").unwrap();
+        assert_eq!(captures.pos(0), Some((1, 6)));
+        assert_eq!(captures.at(1), Some("éé"));
+    }
+
+    pub fn invalid_pattern<E: RegexEngine>() {
+        assert!(Regex::<E>::compile(r"(a").is_err());
+    }
+
+    pub fn lookaround<E: RegexEngine>() {
+        let ahead = Regex::<E>::compile(r"^a+(?=b)").unwrap();
+        assert_eq!(ahead.captures("aab").unwrap().at(0), Some("aa"));
+        assert!(ahead.captures("aac").is_none());
+
+        let behind = Regex::<E>::compile(r"(?<=x)y").unwrap();
+        assert_eq!(behind.captures("yxy").unwrap().pos(0), Some((2, 3)));
+        assert!(Regex::<E>::compile(r"(?<!x)y")
+            .unwrap()
+            .captures("xy")
+            .is_none());
+    }
+
+    pub fn backreference<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"^(`+)(.+?)\1").unwrap();
+        assert_eq!(regex.captures("``a`b``").unwrap().at(2), Some("a`b"));
+    }
+
+    pub fn word_boundary<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"\bab\b").unwrap();
+        assert_eq!(regex.captures("cab ab").unwrap().pos(0), Some((4, 6)));
+    }
+
+    pub fn named_groups<E: RegexEngine>() {
+        let regex =
+            Regex::<E>::compile(r"^(?<name>\w+)(?::(?<id>\d+))?(?<ticks>`+)x\k<ticks>").unwrap();
+        let captures = regex.captures("abc``x``").unwrap();
+        assert_eq!(captures.name("name"), Some("abc"));
+        assert_eq!(captures.name_pos("ticks"), Some((3, 5)));
+        assert_eq!(captures.name("id"), None);
+        assert_eq!(
+            captures.try_name("id"),
+            Err(RuleError::MissingNamedGroup("id".to_owned()))
+        );
+        assert_eq!(captures.name("nope"), None);
+        let mut names: Vec<_> = captures.names().collect();
+        names.sort();
+        assert_eq!(names, [("id", 2), ("name", 1), ("ticks", 3)]);
+    }
+
+    pub fn mixed_groups<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"^(a)(?<b>b)(c)").unwrap();
+        let captures = regex.captures("abc").unwrap();
+        assert_eq!(captures.len(), 4);
+        assert_eq!(captures.at(3), Some("c"));
+        assert_eq!(captures.name_pos("b"), Some((1, 2)));
+    }
+
+    pub fn group_iteration<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"^(a)(x)?(é)").unwrap();
+        let captures = regex.captures("aé!").unwrap();
+        assert_eq!(captures.len(), 4);
+        let groups: Vec<_> = captures.iter().collect();
+        assert_eq!(
+            groups,
+            [
+                Some(Group {
+                    start: 0,
+                    end: 3,
+                    text: "aé"
+                }),
+                Some(Group {
+                    start: 0,
+                    end: 1,
+                    text: "a"
+                }),
+                None,
+                Some(Group {
+                    start: 1,
+                    end: 3,
+                    text: "é"
+                }),
+            ]
+        );
+    }
+
+    pub fn owned_captures<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"^(?<word>\w+)(?=(!))").unwrap();
+        let owned = {
+            let text = String::from("hi! there");
+            regex.captures(&text).unwrap().into_owned()
+        };
+        assert_eq!(owned.at(0), Some("hi"));
+        assert_eq!(owned.at(2), Some("!"));
+        assert_eq!(owned.name("word"), Some("hi"));
+        assert_eq!(owned.len(), 3);
+        assert_eq!(owned.as_captures().try_pos(1), Ok((0, 2)));
+
+        // A match further into the source keeps indexing it
+        let regex = Regex::<E>::compile(r"\G(?<word>\w+)(?=(!))").unwrap();
+        let owned = {
+            let text = format!("{} hi! there", "x".repeat(1000));
+            regex
+                .captures_at(&text, 1001, text.len())
+                .unwrap()
+                .unwrap()
+                .into_owned()
+        };
+        assert_eq!(owned.pos(0), Some((1001, 1003)));
+        assert_eq!(owned.at(2), Some("!"));
+        assert_eq!(owned.name("word"), Some("hi"));
+        let groups: Vec<_> = owned.as_captures().iter().flatten().collect();
+        assert_eq!(
+            groups
+                .iter()
+                .map(|group| (group.start, group.text))
+                .collect::<Vec<_>>(),
+            [(1001, "hi"), (1001, "hi"), (1003, "!")]
+        );
+    }
+
+    pub fn offset_matching<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"\G\b_(\w+)_\b").unwrap();
+        assert_eq!(
+            regex.captures_at("a _b_ c", 2, 7).unwrap().unwrap().pos(1),
+            Some((3, 4))
+        );
+        // `\b` sees the text on both sides of the range
+        assert!(regex.captures_at("a_b_ c", 1, 6).unwrap().is_none());
+        assert!(regex.captures("_b_ c").is_some());
+        assert!(regex.captures_at("a _b_c", 2, 5).unwrap().is_some());
+        assert!(regex.captures_at("a _b_c", 2, 6).unwrap().is_none());
+
+        let behind = Regex::<E>::compile(r"\G(?<=x)y").unwrap();
+        assert_eq!(
+            behind.captures_at("xy", 1, 2).unwrap().unwrap().at(0),
+            Some("y")
+        );
+        assert!(behind.captures_at("zy", 1, 2).unwrap().is_none());
+
+        // Matches that would start after `start` don't count
+        let unanchored = Regex::<E>::compile(r"y").unwrap();
+        assert!(unanchored.captures_at("xxy", 1, 3).unwrap().is_none());
+        assert!(unanchored.captures_at("xxy", 2, 3).unwrap().is_some());
+    }
+
+    pub fn case_insensitive<E: RegexEngine>() {
+        let options = RegexOptions::new().case_insensitive(true);
+        let regex = Regex::<E>::compile_with_options(r"^ab", options).unwrap();
+        assert_eq!(regex.captures("ABc").unwrap().at(0), Some("AB"));
+        assert!(Regex::<E>::compile(r"^ab")
+            .unwrap()
+            .captures("ABc")
+            .is_none());
+    }
+
+    pub fn backtrack_limit<E: RegexEngine>() {
+        let options = RegexOptions::new().backtrack_limit(1000);
+        // Backtracks exponentially when the `a`s and `b` aren't at the end
+        let regex = Regex::<E>::compile_with_options(r"\G(?:a|a)*b$", options).unwrap();
+        let text = "a".repeat(40) + "b!";
+        assert!(matches!(
+            regex.captures_at(&text, 0, text.len()),
+            Err(LimitExceeded)
+        ));
+        assert!(regex.captures(&text).is_none());
+        assert!(regex.captures_at(&text, 0, 41).unwrap().is_some());
+    }
+
+    pub fn unicode_classes<E: RegexEngine>() {
+        let regex = Regex::<E>::compile(r"^\w+\s\d+\b").unwrap();
+        assert_eq!(
+            regex.captures("héllo\u{a0}١٢ x").unwrap().at(0),
+            Some("héllo\u{a0}١٢")
+        );
+        // `é` is a word character, so there's no boundary before the `x`
+        assert!(Regex::<E>::compile(r"\bx")
+            .unwrap()
+            .captures("éx")
+            .is_none());
+    }
+}
@@ -0,0 +1,108 @@
+//! Adding, switching off and looking up rules in a `RuleSet`
+
+mod common;
+
+use common::outline;
+use simple_ast::markdown_rules::{embed_description, message_content, Bold, Italic, Text};
+use simple_ast::{MarkdownNode, Parser, Rule, RuleSet, RuleSetError};
+
+#[test]
+fn insertion_order() {
+    let mut rules: RuleSet<MarkdownNode> = RuleSet::new().with("text", Text);
+    rules.insert_before("text", "bold", Bold).unwrap();
+    rules.insert_after("bold", "italic", Italic).unwrap();
+    assert_eq!(
+        rules.names().collect::<Vec<_>>(),
+        ["bold", "italic", "text"]
+    );
+    assert_eq!(rules.len(), 3);
+
+    assert_eq!(
+        rules.push("bold", Bold),
+        Err(RuleSetError::Duplicate("bold".to_owned()))
+    );
+    assert_eq!(
+        rules.insert_before("missing", "underline", Bold),
+        Err(RuleSetError::Unknown("missing".to_owned()))
+    );
+    assert_eq!(rules.len(), 3);
+}
+
+#[test]
+fn rules_are_tried_in_order() {
+    // Text matches anything, so nothing after it gets a chance
+    let rules: RuleSet<MarkdownNode> = RuleSet::new().with("text", Text).with("bold", Bold);
+    let parser = Parser::with_rule_set(rules);
+    assert_eq!(
+        outline(&parser.parse("**a**").nodes),
+        r#"Text("*"), Text("*a"), Text("*"), Text("*")"#
+    );
+}
+
+#[test]
+fn disabling() {
+    let mut parser: Parser<MarkdownNode> = Parser::with_rule_set(message_content());
+    parser.rules_mut().disable("user_mention").unwrap();
+    assert_eq!(parser.rules().is_enabled("user_mention"), Some(false));
+    assert_eq!(
+        outline(&parser.parse("<@1> **a**").nodes),
+        r#"Text("<"), Text("@1"), Text("> "), Bold[Text("a")]"#
+    );
+    parser.rules_mut().enable("user_mention").unwrap();
+    assert_eq!(
+        outline(&parser.parse("<@1> **a**").nodes),
+        r#"UserMention(1), Text(" "), Bold[Text("a")]"#
+    );
+    assert_eq!(
+        parser.rules_mut().disable("missing"),
+        Err(RuleSetError::Unknown("missing".to_owned()))
+    );
+    assert_eq!(parser.rules().is_enabled("missing"), None);
+}
+
+#[test]
+fn named_lookup() {
+    let rules: RuleSet<MarkdownNode> = message_content();
+    assert!(rules.contains("spoiler"));
+    assert_eq!(rules.get("spoiler").unwrap().first_bytes(), Some(&b"|"[..]));
+    assert!(rules.get("missing").is_none());
+    assert_eq!(rules.names().next(), Some("escape"));
+    assert_eq!(rules.names().last(), Some("text"));
+}
+
+#[test]
+fn from_a_list_of_rules() {
+    // Rules are named after `Rule::name`, repeats get a number
+    let list: Vec<Box<dyn Rule<MarkdownNode>>> =
+        vec![Box::new(Bold), Box::new(Bold), Box::new(Text)];
+    let rules = RuleSet::from(list);
+    assert_eq!(rules.names().collect::<Vec<_>>(), ["Bold", "Bold2", "Text"]);
+}
+
+#[test]
+fn disabling_reaches_nested_sets() {
+    let mut rules: RuleSet<MarkdownNode> = embed_description();
+    rules.disable("bold").unwrap();
+    assert_eq!(rules.is_enabled("bold"), Some(false));
+    assert_eq!(
+        rules.nested("masked_link_text").unwrap().is_enabled("bold"),
+        Some(false)
+    );
+    let parser = Parser::with_rule_set(rules.clone());
+    assert_eq!(
+        outline(&parser.parse("**a** [**b**](https://x)").nodes),
+        r#"Text("*"), Text("*a"), Text("*"), Text("* "), MaskedLink[Text("*"), Text("*b"), Text("*"), Text("*")]"#
+    );
+
+    rules.enable("bold").unwrap();
+    let parser = Parser::with_rule_set(rules.clone());
+    assert_eq!(
+        outline(&parser.parse("**a** [**b**](https://x)").nodes),
+        r#"Bold[Text("a")], Text(" "), MaskedLink[Bold[Text("b")]]"#
+    );
+
+    rules.remove("bold").unwrap();
+    assert!(!rules.contains("bold"));
+    assert!(!rules.nested("masked_link_text").unwrap().contains("bold"));
+    assert!(rules.remove("bold").is_err());
+}